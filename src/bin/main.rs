@@ -1,45 +1,110 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use clap::Parser;
 use tokio::sync::mpsc;
 use websocket_cli_chat::config::cli::CliArgs;
-use websocket_cli_chat::ui::run_ui;
+use websocket_cli_chat::ui::{UiState, run_ui};
 
 use websocket_cli_chat::config::{Config, SharedConfig};
-use websocket_cli_chat::models::message::Message;
-use websocket_cli_chat::network::{client, server};
+use websocket_cli_chat::identity::Identity;
+use websocket_cli_chat::identity::known_peers::KnownPeers;
+use websocket_cli_chat::network::hub::Hub;
+use websocket_cli_chat::network::mesh::Mesh;
+use websocket_cli_chat::network::{NetCommand, NetEvent, discovery, noise, server};
+use websocket_cli_chat::storage::history::History;
 
 #[tokio::main]
 async fn main() {
     let args = CliArgs::parse();
     let config: SharedConfig = Arc::new(Config::from_args(&args));
 
-    let (net_tx, net_rx) = mpsc::channel::<Message>(100);
-    let (user_tx, user_rx) = mpsc::channel::<Message>(100);
+    let (net_tx, net_rx) = mpsc::channel::<NetCommand>(100);
+    let (user_tx, user_rx) = mpsc::channel::<NetEvent>(100);
+
+    let identity = match Identity::load_or_generate(config.config_dir()) {
+        Ok(identity) => identity,
+        Err(e) => {
+            eprintln!("Ошибка загрузки ключа личности: {}", e);
+            return;
+        }
+    };
+    let known_peers = match KnownPeers::load(config.config_dir()) {
+        Ok(known_peers) => known_peers,
+        Err(e) => {
+            eprintln!("Ошибка чтения known_peers: {}", e);
+            return;
+        }
+    };
+
+    let history = match History::open(config.config_dir()) {
+        Ok(history) => Arc::new(Mutex::new(history)),
+        Err(e) => {
+            eprintln!("Ошибка открытия истории: {}", e);
+            return;
+        }
+    };
+
+    // ключи грузим один раз, чтобы сервер и клиент не сгенерировали их одновременно
+    let noise_keys = if config.noise_enabled() {
+        match noise::load_or_generate_keys(config.noise_key()) {
+            Ok(keys) => {
+                let notice = format!("Noise включён, ваш ключ: {}", keys.public_hex());
+                let _ = user_tx.send(NetEvent::Notice(notice)).await;
+                Some(Arc::new(keys))
+            }
+            Err(e) => {
+                eprintln!("Ошибка загрузки ключа Noise: {}", e);
+                return;
+            }
+        }
+    } else {
+        None
+    };
 
     let (server_ready_tx, server_ready_rx) = tokio::sync::oneshot::channel::<()>();
-    let (client_ready_tx, client_ready_rx) = tokio::sync::oneshot::channel::<()>();
 
-    let mut server =
-        server::WebSocketServer::new(Arc::clone(&config), user_tx.clone(), server_ready_tx);
-    let mut client = client::PeerClient::new(
+    // хаб сам ни к кому не подключается: его сообщения расходятся по реестру клиентов
+    let hub = config.hub_enabled().then(|| Hub::new(user_tx.clone()));
+    let mesh = Mesh::new(
         Arc::clone(&config),
         user_tx.clone(),
-        net_rx,
-        client_ready_tx,
+        noise_keys,
+        hub,
+        Arc::clone(&history),
+        Arc::new(identity.clone()),
     );
 
+    let mut server = server::WebSocketServer::new(mesh.clone(), server_ready_tx);
     tokio::spawn(async move { server.run().await });
-    tokio::spawn(async move { client.run().await });
 
-    //ждём старта серва и клиента перед вводом сообщений
-    let _ = (server_ready_rx.await, client_ready_rx.await);
-    run_ui(
-        user_rx,
-        net_tx.clone(),
+    // клиенты подключаются в фоне: пока пира нет, сообщения копятся в очереди
+    if !config.hub_enabled() {
+        for addr in config.peer_addrs() {
+            mesh.dial(addr);
+        }
+    }
+    let states_rx = mesh.subscribe_states();
+    tokio::spawn(mesh.relay_local(net_rx));
+
+    if config.discovery_enabled() {
+        let fingerprint = identity.fingerprint();
+        tokio::spawn(discovery::run(
+            Arc::clone(&config),
+            fingerprint,
+            user_tx.clone(),
+        ));
+    }
+
+    //ждём старта серва перед вводом сообщений
+    let _ = server_ready_rx.await;
+    let state = UiState::new(
         config.username().to_string(),
-        config.token().to_string(),
-    )
-    .await
-    .unwrap();
+        identity,
+        known_peers,
+        config.read_receipts(),
+        history,
+    );
+    run_ui(user_rx, net_tx.clone(), states_rx, state)
+        .await
+        .unwrap();
 }
@@ -1,4 +1,4 @@
-use clap::{Parser, command};
+use clap::Parser;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -14,4 +14,55 @@ pub struct CliArgs {
 
     #[arg(default_value = "default_token")]
     pub token: String,
+
+    // дополнительные собеседники для mesh-режима, флаг можно повторять
+    #[arg(long = "peer")]
+    pub peers: Vec<String>,
+
+    #[arg(long)]
+    pub config_dir: Option<String>,
+
+    #[arg(long)]
+    pub hub: bool,
+
+    #[arg(long)]
+    pub tls: bool,
+
+    // по умолчанию cert.pem и key.pem в каталоге настроек
+    #[arg(long)]
+    pub tls_cert: Option<String>,
+
+    #[arg(long)]
+    pub tls_key: Option<String>,
+
+    #[arg(long)]
+    pub tls_self_signed: bool,
+
+    #[arg(long = "peer-fingerprint")]
+    pub peer_fingerprints: Vec<String>,
+
+    #[arg(long)]
+    pub noise: bool,
+
+    // по умолчанию noise.key в каталоге настроек
+    #[arg(long)]
+    pub noise_key: Option<String>,
+
+    #[arg(long = "noise-peer-key")]
+    pub noise_peer_keys: Vec<String>,
+
+    #[arg(long)]
+    pub discovery: bool,
+
+    #[arg(long)]
+    pub max_attempts: Option<u32>,
+
+    #[arg(long)]
+    pub persist_outbox: bool,
+
+    #[arg(long)]
+    pub read_receipts: bool,
+
+    #[arg(long)]
+    pub download_dir: Option<String>,
 }
@@ -1,3 +1,4 @@
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 pub mod cli;
@@ -5,8 +6,23 @@ pub mod cli;
 pub trait ConfigProvider {
     fn server_addr(&self) -> &str;
     fn token(&self) -> &str;
-    fn peer_addr(&self) -> &str;
+    fn peer_addrs(&self) -> &[String];
     fn username(&self) -> &str;
+    fn config_dir(&self) -> &Path;
+    fn hub_enabled(&self) -> bool;
+    fn tls_enabled(&self) -> bool;
+    fn tls_cert(&self) -> &Path;
+    fn tls_key(&self) -> &Path;
+    fn tls_self_signed(&self) -> bool;
+    fn peer_fingerprints(&self) -> &[String];
+    fn noise_enabled(&self) -> bool;
+    fn noise_key(&self) -> &Path;
+    fn noise_peer_keys(&self) -> &[String];
+    fn discovery_enabled(&self) -> bool;
+    fn max_attempts(&self) -> Option<u32>;
+    fn persist_outbox(&self) -> bool;
+    fn read_receipts(&self) -> bool;
+    fn download_dir(&self) -> &Path;
 }
 
 pub type SharedConfig = Arc<dyn ConfigProvider + Send + Sync>;
@@ -15,8 +31,24 @@ pub type SharedConfig = Arc<dyn ConfigProvider + Send + Sync>;
 pub struct Config {
     pub server_addr: String,
     pub token: String,
-    pub peer_addr: String,
+    pub peer_addrs: Vec<String>,
     pub username: String,
+    pub config_dir: PathBuf,
+    pub hub_enabled: bool,
+    pub tls_enabled: bool,
+    pub tls_cert: PathBuf,
+    pub tls_key: PathBuf,
+    pub tls_self_signed: bool,
+    pub peer_fingerprints: Vec<String>,
+    pub noise_enabled: bool,
+    pub noise_key: PathBuf,
+    pub noise_peer_keys: Vec<String>,
+    pub discovery_enabled: bool,
+    // None — переподключаемся бесконечно
+    pub max_attempts: Option<u32>,
+    pub persist_outbox: bool,
+    pub read_receipts: bool,
+    pub download_dir: PathBuf,
 }
 
 impl ConfigProvider for Config {
@@ -26,21 +58,120 @@ impl ConfigProvider for Config {
     fn token(&self) -> &str {
         &self.token
     }
-    fn peer_addr(&self) -> &str {
-        &self.peer_addr
+    fn peer_addrs(&self) -> &[String] {
+        &self.peer_addrs
     }
     fn username(&self) -> &str {
         &self.username
     }
+    fn config_dir(&self) -> &Path {
+        &self.config_dir
+    }
+    fn hub_enabled(&self) -> bool {
+        self.hub_enabled
+    }
+    fn tls_enabled(&self) -> bool {
+        self.tls_enabled
+    }
+    fn tls_cert(&self) -> &Path {
+        &self.tls_cert
+    }
+    fn tls_key(&self) -> &Path {
+        &self.tls_key
+    }
+    fn tls_self_signed(&self) -> bool {
+        self.tls_self_signed
+    }
+    fn peer_fingerprints(&self) -> &[String] {
+        &self.peer_fingerprints
+    }
+    fn noise_enabled(&self) -> bool {
+        self.noise_enabled
+    }
+    fn noise_key(&self) -> &Path {
+        &self.noise_key
+    }
+    fn noise_peer_keys(&self) -> &[String] {
+        &self.noise_peer_keys
+    }
+    fn discovery_enabled(&self) -> bool {
+        self.discovery_enabled
+    }
+    fn max_attempts(&self) -> Option<u32> {
+        self.max_attempts
+    }
+    fn persist_outbox(&self) -> bool {
+        self.persist_outbox
+    }
+    fn read_receipts(&self) -> bool {
+        self.read_receipts
+    }
+    fn download_dir(&self) -> &Path {
+        &self.download_dir
+    }
 }
 
 impl Config {
     pub fn from_args(args: &cli::CliArgs) -> Self {
+        let config_dir = args
+            .config_dir
+            .as_ref()
+            .map(PathBuf::from)
+            .unwrap_or_else(default_config_dir);
         Config {
             server_addr: args.server_addr.clone(),
             token: args.token.clone(),
-            peer_addr: args.peer_addr.clone(),
+            peer_addrs: peer_addrs(args),
             username: args.username.clone(),
+            download_dir: args
+                .download_dir
+                .as_ref()
+                .map(PathBuf::from)
+                .unwrap_or_else(|| default_download_dir(&config_dir)),
+            tls_cert: in_config_dir(&args.tls_cert, &config_dir, "cert.pem"),
+            tls_key: in_config_dir(&args.tls_key, &config_dir, "key.pem"),
+            noise_key: in_config_dir(&args.noise_key, &config_dir, "noise.key"),
+            config_dir,
+            hub_enabled: args.hub,
+            tls_enabled: args.tls,
+            tls_self_signed: args.tls_self_signed,
+            peer_fingerprints: args.peer_fingerprints.clone(),
+            noise_enabled: args.noise,
+            noise_peer_keys: args.noise_peer_keys.clone(),
+            discovery_enabled: args.discovery,
+            max_attempts: args.max_attempts,
+            persist_outbox: args.persist_outbox,
+            read_receipts: args.read_receipts,
+        }
+    }
+}
+
+// ~/.config/websocket-cli-chat на linux, аналоги на других ос
+fn default_config_dir() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("websocket-cli-chat")
+}
+
+// ключи и сертификаты по умолчанию лежат в каталоге настроек, а не там, откуда запустили чат
+fn in_config_dir(path: &Option<String>, config_dir: &Path, default: &str) -> PathBuf {
+    path.as_ref()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| config_dir.join(default))
+}
+
+// ~/Downloads, а если такой папки нет — рядом с настройками
+fn default_download_dir(config_dir: &Path) -> PathBuf {
+    dirs::download_dir().unwrap_or_else(|| config_dir.join("downloads"))
+}
+
+// позиционный адрес собеседника плюс все --peer, без повторов
+fn peer_addrs(args: &cli::CliArgs) -> Vec<String> {
+    let mut addrs = vec![args.peer_addr.clone()];
+    for addr in &args.peers {
+        if !addrs.contains(addr) {
+            addrs.push(addr.clone());
         }
     }
+    addrs
 }
@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+const KNOWN_PEERS_FILE: &str = "known_peers";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerTrust {
+    Known,
+    New,
+    KeyChanged { pinned: String },
+}
+
+// trust on first use: первое увиденное имя закрепляется за ключом навсегда
+pub struct KnownPeers {
+    path: PathBuf,
+    peers: HashMap<String, String>,
+}
+
+impl KnownPeers {
+    // формат файла: "<имя> <публичный ключ>" по строке на собеседника
+    pub fn load(config_dir: &Path) -> io::Result<Self> {
+        let path = config_dir.join(KNOWN_PEERS_FILE);
+        let mut peers = HashMap::new();
+
+        if path.exists() {
+            for line in std::fs::read_to_string(&path)?.lines() {
+                if let Some((name, key)) = line.trim().rsplit_once(' ') {
+                    // первое закрепление остаётся в силе: дописанная позже строка его не подменит
+                    peers
+                        .entry(name.to_string())
+                        .or_insert_with(|| key.to_string());
+                }
+            }
+        }
+
+        Ok(Self { path, peers })
+    }
+
+    pub fn check(&mut self, name: &str, public_key: &str) -> io::Result<PeerTrust> {
+        match self.peers.get(name) {
+            Some(pinned) if pinned == public_key => Ok(PeerTrust::Known),
+            Some(pinned) => Ok(PeerTrust::KeyChanged {
+                pinned: pinned.clone(),
+            }),
+            None => {
+                self.pin(name, public_key)?;
+                Ok(PeerTrust::New)
+            }
+        }
+    }
+
+    // закреплённый ключ — им шифруются личные сообщения этому собеседнику
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.peers.get(name).map(String::as_str)
+    }
+
+    fn pin(&mut self, name: &str, public_key: &str) -> io::Result<()> {
+        if !valid_name(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("недопустимое имя {:?}", name),
+            ));
+        }
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{} {}", name, public_key)?;
+
+        self.peers.insert(name.to_string(), public_key.to_string());
+        Ok(())
+    }
+}
+
+// имя пишется в файл как есть: пробел или перевод строки позволили бы дописать чужое закрепление
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.chars().any(|c| c.is_whitespace() || c.is_control())
+}
@@ -0,0 +1,238 @@
+use std::io;
+use std::path::Path;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::models::message::Message;
+
+pub mod known_peers;
+
+const IDENTITY_FILE: &str = "identity.key";
+// метка в metadata личного сообщения: hex от nonce, содержимое — base64 шифротекста
+pub const DIRECT_NONCE: &str = "e2e";
+const DIRECT_KEY_CONTEXT: &[u8] = b"websocket-cli-chat direct v1";
+
+#[derive(Clone)]
+pub struct Identity {
+    signing_key: SigningKey,
+}
+
+impl Identity {
+    // ключ хранится в каталоге настроек как hex от 32-байтового seed
+    pub fn load_or_generate(config_dir: &Path) -> io::Result<Self> {
+        let path = config_dir.join(IDENTITY_FILE);
+
+        if path.exists() {
+            let seed = hex::decode(std::fs::read_to_string(&path)?.trim())
+                .ok()
+                .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "повреждён файл identity.key")
+                })?;
+            return Ok(Self {
+                signing_key: SigningKey::from_bytes(&seed),
+            });
+        }
+
+        let mut seed = [0u8; 32];
+        rand::rng().fill_bytes(&mut seed);
+
+        std::fs::create_dir_all(config_dir)?;
+        std::fs::write(&path, hex::encode(seed))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        }
+
+        Ok(Self {
+            signing_key: SigningKey::from_bytes(&seed),
+        })
+    }
+
+    pub fn public_key(&self) -> String {
+        hex::encode(self.signing_key.verifying_key().as_bytes())
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public_key())
+    }
+
+    pub fn sign_message(&self, message: &mut Message) {
+        message.public_key = Some(self.public_key());
+        let signature = self.signing_key.sign(&message.signing_bytes());
+        message.signature = Some(hex::encode(signature.to_bytes()));
+    }
+
+    // подпись под удалением сообщения id в момент at (мс); возвращает (публичный ключ, подпись)
+    pub fn sign_deletion(&self, id: &str, at: i64) -> (String, String) {
+        let signature = self.signing_key.sign(&deletion_bytes(id, at));
+        (self.public_key(), hex::encode(signature.to_bytes()))
+    }
+
+    // подпись под входом: имя из hello и nonce сервера, чтобы её нельзя было повторить
+    pub fn sign_auth(&self, username: &str, nonce: &str) -> (String, String) {
+        let signature = self.signing_key.sign(&auth_bytes(username, nonce));
+        (self.public_key(), hex::encode(signature.to_bytes()))
+    }
+
+    // подпись под реакцией: кто её поставил, решает ключ, а не имя
+    pub fn sign_reaction(&self, id: &str, emoji: &str, on: bool, at: i64) -> (String, String) {
+        let signature = self.signing_key.sign(&reaction_bytes(id, emoji, on, at));
+        (self.public_key(), hex::encode(signature.to_bytes()))
+    }
+
+    // общий ключ с собеседником: X25519 поверх тех же ed25519-ключей, с обеих сторон одинаковый
+    fn direct_key(&self, public_key: &str) -> Option<ChaCha20Poly1305> {
+        let peer = parse_public_key(public_key)?;
+        let shared = peer
+            .to_montgomery()
+            .mul_clamped(self.signing_key.to_scalar_bytes());
+        let key = Sha256::new()
+            .chain_update(DIRECT_KEY_CONTEXT)
+            .chain_update(shared.as_bytes())
+            .finalize();
+        ChaCha20Poly1305::new_from_slice(&key).ok()
+    }
+
+    // шифруем текст личного сообщения ключом получателя; подписывать нужно уже после
+    pub fn seal_direct(&self, message: &mut Message, recipient_key: &str) -> bool {
+        let Some(cipher) = self.direct_key(recipient_key) else {
+            return false;
+        };
+        let mut nonce = [0u8; 12];
+        rand::rng().fill_bytes(&mut nonce);
+        let payload = Payload {
+            msg: message.content.as_bytes(),
+            aad: message.id.as_bytes(),
+        };
+        let Ok(ciphertext) = cipher.encrypt(Nonce::from_slice(&nonce), payload) else {
+            return false;
+        };
+        message.content = BASE64.encode(ciphertext);
+        message
+            .metadata
+            .insert(DIRECT_NONCE.to_string(), hex::encode(nonce));
+        true
+    }
+
+    // расшифровываем ключом отправителя из самого сообщения — подпись к этому моменту уже проверена
+    pub fn open_direct(&self, message: &mut Message) -> Result<(), String> {
+        let Some(nonce) = message.metadata.get(DIRECT_NONCE) else {
+            return Ok(());
+        };
+        let nonce = hex::decode(nonce)
+            .ok()
+            .filter(|nonce| nonce.len() == 12)
+            .ok_or("некорректный nonce")?;
+        let cipher = message
+            .public_key
+            .as_deref()
+            .and_then(|key| self.direct_key(key))
+            .ok_or("нет ключа отправителя")?;
+        let ciphertext = BASE64
+            .decode(&message.content)
+            .map_err(|_| "содержимое не в base64")?;
+        let payload = Payload {
+            msg: &ciphertext,
+            aad: message.id.as_bytes(),
+        };
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| "не удалось расшифровать")?;
+        message.content = String::from_utf8(plaintext).map_err(|_| "текст не в UTF-8")?;
+        Ok(())
+    }
+}
+
+fn deletion_bytes(id: &str, at: i64) -> Vec<u8> {
+    serde_json::to_vec(&("delete", id, at)).unwrap()
+}
+
+// удалить сообщение может только владелец ключа, которым оно подписано
+pub fn verify_deletion(id: &str, at: i64, public_key: &str, signature: &str) -> bool {
+    verify_bytes(&deletion_bytes(id, at), public_key, signature)
+}
+
+fn reaction_bytes(id: &str, emoji: &str, on: bool, at: i64) -> Vec<u8> {
+    serde_json::to_vec(&("reaction", id, emoji, on, at)).unwrap()
+}
+
+pub fn verify_reaction(
+    id: &str,
+    emoji: &str,
+    on: bool,
+    at: i64,
+    public_key: &str,
+    signature: &str,
+) -> bool {
+    verify_bytes(&reaction_bytes(id, emoji, on, at), public_key, signature)
+}
+
+fn auth_bytes(username: &str, nonce: &str) -> Vec<u8> {
+    serde_json::to_vec(&("auth", username, nonce)).unwrap()
+}
+
+pub fn verify_auth(username: &str, nonce: &str, public_key: &str, signature: &str) -> bool {
+    verify_bytes(&auth_bytes(username, nonce), public_key, signature)
+}
+
+fn verify_bytes(bytes: &[u8], public_key: &str, signature: &str) -> bool {
+    let signature = hex::decode(signature)
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok());
+    match (parse_public_key(public_key), signature) {
+        (Some(key), Some(signature)) => key.verify(bytes, &signature).is_ok(),
+        _ => false,
+    }
+}
+
+fn parse_public_key(public_key: &str) -> Option<VerifyingKey> {
+    hex::decode(public_key)
+        .ok()
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+}
+
+// короткий отпечаток для показа человеку: первые 8 байт sha256 от публичного ключа
+pub fn fingerprint(public_key: &str) -> String {
+    let digest = Sha256::digest(public_key.as_bytes());
+    digest[..8]
+        .chunks(2)
+        .map(hex::encode)
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureStatus {
+    Valid,
+    Unsigned,
+    Invalid,
+}
+
+pub fn verify_message(message: &Message) -> SignatureStatus {
+    let (Some(public_key), Some(signature)) = (&message.public_key, &message.signature) else {
+        return SignatureStatus::Unsigned;
+    };
+
+    let key = parse_public_key(public_key);
+    let signature = hex::decode(signature)
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok());
+
+    match (key, signature) {
+        (Some(key), Some(signature))
+            if key.verify(&message.signing_bytes(), &signature).is_ok() =>
+        {
+            SignatureStatus::Valid
+        }
+        _ => SignatureStatus::Invalid,
+    }
+}
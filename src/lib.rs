@@ -1,4 +1,6 @@
 pub mod config;
+pub mod identity;
 pub mod models;
 pub mod network;
+pub mod storage;
 pub mod ui;
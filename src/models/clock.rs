@@ -0,0 +1,116 @@
+use serde::{Deserialize, Serialize};
+
+// часы собеседника, убежавшие дальше этого, не утягивают наши за собой
+const MAX_DRIFT_MS: i64 = 60_000;
+
+// отметка гибридных логических часов: физическое время в мс и счётчик внутри одной мс
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Hlc {
+    pub wall: i64,
+    pub logical: u32,
+}
+
+// HLC: отметка ответа больше отметки того, на что отвечали, даже если часы на машинах
+// расходятся — но только пока расхождение не больше MAX_DRIFT_MS; отметку из более далёкого
+// будущего observe пропускает, и ответ на такое сообщение встанет раньше него
+#[derive(Debug, Default)]
+pub struct HybridClock {
+    last: Hlc,
+}
+
+impl HybridClock {
+    // отметка для нового локального события
+    pub fn tick(&mut self) -> Hlc {
+        let now = now_ms();
+        self.last = if now > self.last.wall {
+            Hlc {
+                wall: now,
+                logical: 0,
+            }
+        } else {
+            Hlc {
+                wall: self.last.wall,
+                logical: self.last.logical + 1,
+            }
+        };
+        self.last
+    }
+
+    // последняя выданная или учтённая отметка
+    pub fn latest(&self) -> Hlc {
+        self.last
+    }
+
+    // учитываем отметку из пришедшего сообщения
+    pub fn observe(&mut self, remote: Hlc) {
+        let now = now_ms();
+        if remote.wall > now + MAX_DRIFT_MS {
+            return;
+        }
+
+        let wall = now.max(self.last.wall).max(remote.wall);
+        let logical = match (wall == self.last.wall, wall == remote.wall) {
+            (true, true) => self.last.logical.max(remote.logical) + 1,
+            (true, false) => self.last.logical + 1,
+            (false, true) => remote.logical + 1,
+            (false, false) => 0,
+        };
+        self.last = Hlc { wall, logical };
+    }
+}
+
+fn now_ms() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tick_is_monotonic() {
+        let mut clock = HybridClock::default();
+        let mut previous = clock.tick();
+        for _ in 0..1000 {
+            let next = clock.tick();
+            assert!(next > previous);
+            previous = next;
+        }
+    }
+
+    #[test]
+    fn tick_after_observe_is_later_than_remote() {
+        let mut clock = HybridClock::default();
+        let remote = Hlc {
+            wall: now_ms() + MAX_DRIFT_MS / 2,
+            logical: 7,
+        };
+        clock.observe(remote);
+        assert!(clock.latest() > remote);
+        assert!(clock.tick() > remote);
+    }
+
+    #[test]
+    fn observe_never_moves_backwards() {
+        let mut clock = HybridClock::default();
+        let local = clock.tick();
+        clock.observe(Hlc {
+            wall: local.wall - 10_000,
+            logical: 0,
+        });
+        assert!(clock.latest() > local);
+    }
+
+    #[test]
+    fn observe_ignores_remote_beyond_drift() {
+        let mut clock = HybridClock::default();
+        let local = clock.tick();
+        let remote = Hlc {
+            wall: now_ms() + MAX_DRIFT_MS * 10,
+            logical: 0,
+        };
+        clock.observe(remote);
+        assert_eq!(clock.latest(), local);
+        assert!(clock.tick() < remote);
+    }
+}
@@ -1,22 +1,263 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
+use crate::models::clock::Hlc;
+
+// комната, в которую попадают сообщения без явной комнаты — в том числе от старых клиентов
+pub const DEFAULT_ROOM: &str = "general";
+// ключи metadata: когда автор последний раз правил сообщение и когда удалил его
+pub const EDITED: &str = "edited";
+pub const DELETED: &str = "deleted";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    #[default]
+    Text,
+    // служебное сообщение самого чата, по сети не ходит
+    System,
+    // действие от третьего лица: "* alice машет рукой"
+    Action,
+    // уведомление от собеседника, которое не требует ответа
+    Notice,
+    File,
+    // вид, которого эта версия не знает, показываем как текст
+    #[serde(other)]
+    Unknown,
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Message {
+    // клиенты первой версии id не присылали и называли поля author/text
+    #[serde(default = "new_id")]
     pub id: String,
+    #[serde(alias = "author")]
     pub sender: String,
+    #[serde(alias = "text")]
     pub content: String,
-    pub timestamp: String,
-    pub token: String,
+    #[serde(with = "timestamp_format")]
+    pub timestamp: DateTime<Utc>,
+    #[serde(default = "default_room", skip_serializing_if = "is_default_room")]
+    pub room: String,
+    // адресат личного сообщения; у сообщений в комнатах его нет
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    #[serde(default, skip_serializing_if = "is_text")]
+    pub kind: MessageKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    // произвольные пары ключ-значение для расширений без смены формата
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    // гибридные часы отправителя — по ним сообщения упорядочиваются
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock: Option<Hlc>,
+    // счётчик пересылок при госсипе; меняется по пути, поэтому в подпись не входит
+    #[serde(default)]
+    pub hops: u8,
+}
+
+// судьба исходящего сообщения; в протокол не попадает
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DeliveryState {
+    // ждёт в очереди, пока появится соединение
+    Pending,
+    // записано в сокет хотя бы одному собеседнику
+    Sent,
+    // кто-то из собеседников подтвердил получение
+    Delivered,
+    // кто-то из собеседников увидел сообщение на экране
+    Read,
 }
 
 impl Message {
-    pub fn new(sender: String, content: String, timestamp: String, token: String) -> Self {
+    pub fn new(sender: String, content: String, timestamp: DateTime<Utc>) -> Self {
         Message {
-            id: uuid::Uuid::new_v4().to_string(),
+            id: new_id(),
             sender,
             content,
             timestamp,
-            token,
+            room: DEFAULT_ROOM.to_string(),
+            to: None,
+            kind: MessageKind::Text,
+            reply_to: None,
+            metadata: BTreeMap::new(),
+            public_key: None,
+            signature: None,
+            clock: None,
+            hops: 0,
+        }
+    }
+
+    pub fn system(content: String) -> Self {
+        let mut message = Message::new("system".to_string(), content, Utc::now());
+        message.kind = MessageKind::System;
+        message
+    }
+
+    // место в ленте: отметка HLC, а у сообщений без неё — время отправки
+    pub fn order_key(&self) -> (i64, u32) {
+        match self.clock {
+            Some(clock) => (clock.wall, clock.logical),
+            None => (self.timestamp.timestamp_millis(), 0),
         }
     }
+
+    pub fn is_edited(&self) -> bool {
+        self.metadata.contains_key(EDITED)
+    }
+
+    // от удалённого остаётся надгробие: id, автор и время, без текста
+    pub fn is_deleted(&self) -> bool {
+        self.metadata.contains_key(DELETED)
+    }
+
+    // когда автор последний раз правил или удалил сообщение, мс; 0 — не трогал
+    pub fn updated_at(&self) -> i64 {
+        self.metadata
+            .get(DELETED)
+            .or_else(|| self.metadata.get(EDITED))
+            .and_then(|stamp| DateTime::parse_from_rfc3339(stamp).ok())
+            .map_or(0, |stamp| stamp.timestamp_millis())
+    }
+
+    // всё, что покрывается подписью; при добавлении полей их нужно включать сюда
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        // время подписываем в том же виде, в каком оно идёт по сети
+        let base = (
+            &self.id,
+            &self.sender,
+            &self.content,
+            self.timestamp.to_rfc2822(),
+            &self.public_key,
+        );
+        // старые сообщения подписаны меньшим набором полей — их подписи остаются верными
+        let extended =
+            self.kind != MessageKind::Text || self.reply_to.is_some() || !self.metadata.is_empty();
+        if self.to.is_some() {
+            serde_json::to_vec(&(
+                base,
+                &self.clock,
+                self.kind,
+                &self.reply_to,
+                &self.metadata,
+                &self.room,
+                &self.to,
+            ))
+            .unwrap()
+        } else if self.room != DEFAULT_ROOM {
+            serde_json::to_vec(&(
+                base,
+                &self.clock,
+                self.kind,
+                &self.reply_to,
+                &self.metadata,
+                &self.room,
+            ))
+            .unwrap()
+        } else if extended {
+            serde_json::to_vec(&(base, &self.clock, self.kind, &self.reply_to, &self.metadata))
+                .unwrap()
+        } else if let Some(clock) = &self.clock {
+            serde_json::to_vec(&(base, clock)).unwrap()
+        } else {
+            serde_json::to_vec(&base).unwrap()
+        }
+    }
+}
+
+fn new_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+fn default_room() -> String {
+    DEFAULT_ROOM.to_string()
+}
+
+fn is_default_room(room: &str) -> bool {
+    room == DEFAULT_ROOM
+}
+
+fn is_text(kind: &MessageKind) -> bool {
+    *kind == MessageKind::Text
+}
+
+// по сети время ходит в RFC 2822, как раньше; на входе принимаем и RFC 3339
+mod timestamp_format {
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Deserializer, Serializer, de};
+
+    pub fn serialize<S: Serializer>(timestamp: &DateTime<Utc>, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&timestamp.to_rfc2822())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<DateTime<Utc>, D::Error> {
+        let text = String::deserialize(d)?;
+        DateTime::parse_from_rfc2822(&text)
+            .or_else(|_| DateTime::parse_from_rfc3339(&text))
+            .map(|dt| dt.with_timezone(&Utc))
+            .map_err(|_| de::Error::custom(format!("неизвестный формат времени: {}", text)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_legacy_message_with_rfc2822_timestamp() {
+        let json =
+            r#"{"author": "bob", "text": "привет", "timestamp": "Tue, 1 Jul 2003 10:52:37 +0200"}"#;
+        let message: Message = serde_json::from_str(json).unwrap();
+        assert_eq!(message.sender, "bob");
+        assert_eq!(message.content, "привет");
+        assert_eq!(
+            message.timestamp,
+            DateTime::parse_from_rfc3339("2003-07-01T08:52:37Z").unwrap()
+        );
+        assert_eq!(message.room, DEFAULT_ROOM);
+        assert_eq!(message.kind, MessageKind::Text);
+        assert!(!message.id.is_empty());
+        assert!(message.clock.is_none());
+    }
+
+    #[test]
+    fn parses_legacy_message_with_rfc3339_timestamp() {
+        let json =
+            r#"{"author": "bob", "text": "hi", "timestamp": "2024-03-05T12:00:00.250+03:00"}"#;
+        let message: Message = serde_json::from_str(json).unwrap();
+        assert_eq!(message.content, "hi");
+        assert_eq!(message.timestamp.timestamp_millis(), 1_709_629_200_250);
+        assert_eq!(message.order_key(), (1_709_629_200_250, 0));
+    }
+
+    #[test]
+    fn legacy_messages_get_distinct_ids() {
+        let json = r#"{"author": "bob", "text": "hi", "timestamp": "2024-03-05T12:00:00Z"}"#;
+        let first: Message = serde_json::from_str(json).unwrap();
+        let second: Message = serde_json::from_str(json).unwrap();
+        assert_ne!(first.id, second.id);
+    }
+
+    #[test]
+    fn rejects_unknown_timestamp_format() {
+        let json = r#"{"author": "bob", "text": "hi", "timestamp": "вчера"}"#;
+        assert!(serde_json::from_str::<Message>(json).is_err());
+    }
+
+    #[test]
+    fn serializes_with_current_field_names() {
+        let json = r#"{"author": "bob", "text": "hi", "timestamp": "2024-03-05T12:00:00Z"}"#;
+        let message: Message = serde_json::from_str(json).unwrap();
+        let value: serde_json::Value = serde_json::to_value(&message).unwrap();
+        assert_eq!(value["sender"], "bob");
+        assert_eq!(value["content"], "hi");
+        assert!(value.get("author").is_none());
+    }
 }
@@ -1 +1,3 @@
+pub mod clock;
 pub mod message;
+pub mod reaction;
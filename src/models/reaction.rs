@@ -0,0 +1,99 @@
+// короткие коды, которые можно набрать вместо эмодзи: /react :+1:
+const SHORTCODES: &[(&str, &str)] = &[
+    ("+1", "👍"),
+    ("thumbsup", "👍"),
+    ("-1", "👎"),
+    ("thumbsdown", "👎"),
+    ("heart", "❤️"),
+    ("joy", "😂"),
+    ("smile", "😄"),
+    ("wink", "😉"),
+    ("cry", "😢"),
+    ("open_mouth", "😮"),
+    ("thinking", "🤔"),
+    ("eyes", "👀"),
+    ("fire", "🔥"),
+    ("tada", "🎉"),
+    ("clap", "👏"),
+    ("pray", "🙏"),
+    ("ok_hand", "👌"),
+    ("rocket", "🚀"),
+    ("check", "✅"),
+    ("x", "❌"),
+];
+
+// эмодзи — это несколько не-ascii символов без пробелов, не больше этого
+const MAX_EMOJI_CHARS: usize = 8;
+
+pub fn shortcodes() -> impl Iterator<Item = (&'static str, &'static str)> {
+    SHORTCODES.iter().copied()
+}
+
+// ":+1:" -> "👍"; эмодзи как есть проходит проверку и возвращается без изменений
+pub fn parse(input: &str) -> Option<String> {
+    if let Some(code) = input
+        .strip_prefix(':')
+        .and_then(|rest| rest.strip_suffix(':'))
+    {
+        return SHORTCODES
+            .iter()
+            .find(|(name, _)| *name == code)
+            .map(|(_, emoji)| emoji.to_string());
+    }
+    is_emoji(input).then(|| input.to_string())
+}
+
+// чужие реакции проверяем так же: текст и длинные строки под видом эмодзи не принимаем
+pub fn is_emoji(text: &str) -> bool {
+    let count = text.chars().count();
+    count > 0
+        && count <= MAX_EMOJI_CHARS
+        && text
+            .chars()
+            .all(|c| !c.is_ascii() && !c.is_whitespace() && !c.is_alphanumeric())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_shortcodes() {
+        assert_eq!(parse(":+1:").as_deref(), Some("👍"));
+        assert_eq!(parse(":heart:").as_deref(), Some("❤️"));
+        assert_eq!(parse(":unknown:"), None);
+        assert_eq!(parse("::"), None);
+    }
+
+    #[test]
+    fn passes_emoji_through() {
+        assert_eq!(parse("🔥").as_deref(), Some("🔥"));
+        assert_eq!(parse("❤️").as_deref(), Some("❤️"));
+    }
+
+    #[test]
+    fn rejects_text() {
+        assert_eq!(parse("lol"), None);
+        assert_eq!(parse(":+1"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn is_emoji_checks_characters_and_length() {
+        assert!(is_emoji("👍"));
+        assert!(is_emoji("🎉🎉"));
+        assert!(!is_emoji(""));
+        assert!(!is_emoji("a👍"));
+        assert!(!is_emoji("👍 👍"));
+        assert!(!is_emoji("привет"));
+        assert!(!is_emoji(&"🔥".repeat(MAX_EMOJI_CHARS + 1)));
+        assert!(is_emoji(&"🔥".repeat(MAX_EMOJI_CHARS)));
+    }
+
+    #[test]
+    fn every_shortcode_maps_to_emoji() {
+        for (_, emoji) in shortcodes() {
+            assert!(is_emoji(emoji), "{}", emoji);
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const NONCE_LEN: usize = 32;
+
+pub fn generate_nonce() -> String {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::rng().fill_bytes(&mut nonce);
+    hex::encode(nonce)
+}
+
+// ответ на челлендж: HMAC-SHA256(token, nonce) в hex
+pub fn sign_challenge(token: &str, nonce: &str) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(token.as_bytes()).expect("HMAC принимает ключ любой длины");
+    mac.update(nonce.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// verify_slice сравнивает за постоянное время
+pub fn verify_challenge(token: &str, nonce: &str, response: &str) -> bool {
+    let Ok(response) = hex::decode(response) else {
+        return false;
+    };
+    let mut mac =
+        HmacSha256::new_from_slice(token.as_bytes()).expect("HMAC принимает ключ любой длины");
+    mac.update(nonce.as_bytes());
+    mac.verify_slice(&response).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_response_with_same_token() {
+        let nonce = generate_nonce();
+        let response = sign_challenge("secret", &nonce);
+        assert!(verify_challenge("secret", &nonce, &response));
+    }
+
+    #[test]
+    fn rejects_wrong_token() {
+        let nonce = generate_nonce();
+        let response = sign_challenge("secret", &nonce);
+        assert!(!verify_challenge("other", &nonce, &response));
+    }
+
+    #[test]
+    fn rejects_other_nonce() {
+        let response = sign_challenge("secret", &generate_nonce());
+        assert!(!verify_challenge("secret", &generate_nonce(), &response));
+    }
+
+    #[test]
+    fn rejects_bad_hex() {
+        let nonce = generate_nonce();
+        assert!(!verify_challenge("secret", &nonce, "не hex"));
+        assert!(!verify_challenge("secret", &nonce, "abc"));
+        assert!(!verify_challenge("secret", &nonce, ""));
+    }
+}
@@ -1,110 +1,386 @@
-use std::time::Duration;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
-use crate::{config::SharedConfig, models::message::Message, network::message};
+use crate::config::SharedConfig;
+use crate::network::connection::{self, ConnectionState};
+use crate::network::mesh::{Mesh, Origin, RemotePeer};
+use crate::network::message::{SendError, WsSink, WsStream};
+use crate::network::noise::{NoiseKeys, NoiseSession};
+use crate::network::outbox::Outbox;
+use crate::network::protocol::{
+    self, CAP_NOISE, Frame, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, PresenceStatus,
+};
+use crate::network::transfer::{self, Route};
+use crate::network::{NetEvent, auth, message, noise, sync, tls};
 use futures_util::{SinkExt, StreamExt};
 use http::Uri;
 use tokio::net::TcpStream;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::mpsc;
 use tokio::time;
-use tokio_websockets::{ClientBuilder, MaybeTlsStream, WebSocketStream};
+use tokio_websockets::{ClientBuilder, Connector, MaybeTlsStream, WebSocketStream};
+
+type ClientResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+type ClientStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+// соединение после рукопожатия
+struct Established {
+    sink: WsSink<MaybeTlsStream<TcpStream>>,
+    stream: WsStream<MaybeTlsStream<TcpStream>>,
+    session: Option<NoiseSession>,
+    // возможности, которые есть у обеих сторон
+    capabilities: Vec<String>,
+}
 
 pub struct PeerClient {
+    mesh: Mesh,
     config: SharedConfig,
-    user_tx: mpsc::Sender<Message>,
-    net_rx: mpsc::Receiver<Message>,
-    client_ready_tx: Option<oneshot::Sender<()>>,
+    addr: String,
+    user_tx: mpsc::Sender<NetEvent>,
+    outbox: Outbox,
+    noise_keys: Option<Arc<NoiseKeys>>,
 }
 
 impl PeerClient {
-    pub fn new(
-        config: SharedConfig,
-        user_tx: mpsc::Sender<Message>,
-        net_rx: mpsc::Receiver<Message>,
-        client_ready_tx: oneshot::Sender<()>,
-    ) -> Self {
+    pub fn new(mesh: Mesh, addr: String, outbox: Outbox) -> Self {
         Self {
-            config,
-            user_tx,
-            net_rx,
-            client_ready_tx: Some(client_ready_tx),
+            config: mesh.config().clone(),
+            user_tx: mesh.user_tx().clone(),
+            noise_keys: mesh.noise_keys().cloned(),
+            mesh,
+            addr,
+            outbox,
         }
     }
 
     pub async fn run(&mut self) {
-        let (addr, token) = (
-            self.config.peer_addr().to_string(),
-            self.config.token().to_string(),
-        );
+        let Some((scheme, connector)) = self.connector() else {
+            return;
+        };
 
+        let mut attempt = 0;
         loop {
-            let uri: Uri = match format!("ws://{}", addr).parse() {
-                Ok(uri) => uri,
-                Err(e) => {
-                    eprintln!("Ошибка парсинга URI: {}", e);
-                    tokio::time::sleep(time::Duration::from_secs(5)).await;
-                    continue;
-                }
-            };
+            attempt += 1;
+            self.mesh
+                .set_state(&self.addr, ConnectionState::Connecting { attempt });
 
-            match ClientBuilder::from_uri(uri).connect().await {
-                Ok((ws_stream, _response)) => {
-                    if let Some(tx) = self.client_ready_tx.take() {
-                        let _ = tx.send(());
+            let error = match self.connect(scheme, &connector).await {
+                Ok(ws_stream) => match self.handshake(ws_stream).await {
+                    Ok(established) => {
+                        self.mesh.set_state(&self.addr, ConnectionState::Connected);
+                        attempt = 0;
+                        self.serve(established).await;
+                        continue;
                     }
+                    Err(e) => e.to_string(),
+                },
+                Err(e) => e.to_string(),
+            };
 
-                    if let Err(e) = self.handle_connection(ws_stream, token.clone()).await {
-                        eprintln!("Ошибка в обработке соединения: {}", e);
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Ошибка соединения с {}: {}. Повтор через 5 сек", addr, e);
-                    tokio::time::sleep(Duration::from_secs(5)).await;
-                }
+            if self.config.max_attempts().is_some_and(|max| attempt >= max) {
+                self.give_up(format!("{} (попыток: {})", error, attempt));
+                return;
             }
+
+            let delay = connection::backoff_delay(attempt);
+            self.mesh.set_state(
+                &self.addr,
+                ConnectionState::Backoff {
+                    attempt,
+                    delay,
+                    error,
+                },
+            );
+            time::sleep(delay).await;
         }
     }
 
-    async fn handle_connection(
-        &mut self,
-        ws_stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
-        token: String,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    fn give_up(&self, reason: String) {
+        self.mesh
+            .set_state(&self.addr, ConnectionState::Failed(reason));
+        self.mesh.forget(&self.addr);
+    }
+
+    // при TLS доверяем только сертификату с закреплённым отпечатком
+    fn connector(&self) -> Option<(&'static str, Connector)> {
+        if !self.config.tls_enabled() {
+            return Some(("ws", Connector::Plain));
+        }
+        if self.config.peer_fingerprints().is_empty() {
+            self.give_up(
+                "для TLS нужно указать отпечаток сертификата собеседника (--peer-fingerprint)"
+                    .to_string(),
+            );
+            return None;
+        }
+        match tls::pinned_connector(self.config.peer_fingerprints()) {
+            Ok(connector) => Some(("wss", connector)),
+            Err(e) => {
+                self.give_up(format!("ошибка настройки TLS: {}", e));
+                None
+            }
+        }
+    }
+
+    async fn connect(&self, scheme: &str, connector: &Connector) -> ClientResult<ClientStream> {
+        let uri: Uri = format!("{}://{}", scheme, self.addr).parse()?;
+        let (ws_stream, _response) = ClientBuilder::from_uri(uri)
+            .connector(connector)
+            .connect()
+            .await?;
+        Ok(ws_stream)
+    }
+
+    async fn handshake(&mut self, ws_stream: ClientStream) -> ClientResult<Established> {
         let (mut sink, mut stream) = ws_stream.split();
 
-        // отправка об аунтентификации
-        let auth_message = Message::new(
-            "system".to_string(),
-            "auth".to_string(),
-            chrono::Utc::now().to_rfc3339(),
-            token.clone(),
-        );
-        message::send_message(&mut sink, &auth_message).await;
+        // представляемся, договариваемся о версии и проходим авторизацию
+        let local_capabilities = protocol::local_capabilities(self.config.noise_enabled());
+        let username = self.mesh.username();
+        let hello = Frame::Hello {
+            version: PROTOCOL_VERSION,
+            username: username.clone(),
+            min_version: Some(MIN_PROTOCOL_VERSION),
+            capabilities: local_capabilities.clone(),
+        };
+        message::send_frame(&mut sink, &hello).await?;
+
+        // сервер версии 1 welcome не знает и сразу присылает челлендж
+        let mut v1_nonce = None;
+        let capabilities = match message::read_frame(&mut stream).await {
+            Some(Frame::Welcome {
+                version,
+                capabilities,
+                username,
+                hub,
+            }) => {
+                protocol::negotiate_version(version, None)?;
+                self.mesh
+                    .set_remote(&self.addr, RemotePeer { username, hub });
+                protocol::common_capabilities(&local_capabilities, &capabilities)
+            }
+            Some(Frame::Challenge { nonce }) => {
+                protocol::negotiate_version(1, None)?;
+                v1_nonce = Some(nonce);
+                Vec::new()
+            }
+            Some(Frame::Error { message, .. }) => return Err(message.into()),
+            _ => return Err("сервер не ответил на приветствие".into()),
+        };
+        if self.noise_keys.is_some() && !capabilities.iter().any(|c| c == CAP_NOISE) {
+            return Err("собеседник не использует сквозное шифрование (--noise)".into());
+        }
+
+        let nonce = match v1_nonce {
+            Some(nonce) => nonce,
+            None => match message::read_frame(&mut stream).await {
+                Some(Frame::Challenge { nonce }) => nonce,
+                Some(Frame::Error { message, .. }) => return Err(message.into()),
+                _ => return Err("сервер не прислал челлендж".into()),
+            },
+        };
+        let response = auth::sign_challenge(self.config.token(), &nonce);
+        let (public_key, signature) = self.mesh.identity().sign_auth(&username, &nonce);
+        let auth = Frame::Auth {
+            response,
+            public_key: Some(public_key),
+            signature: Some(signature),
+        };
+        message::send_frame(&mut sink, &auth).await?;
+
+        match message::read_frame(&mut stream).await {
+            Some(Frame::AuthOk) => {}
+            Some(Frame::AuthFail { reason }) => return Err(reason.into()),
+            Some(Frame::Error { message, .. }) => return Err(message.into()),
+            _ => return Err("сервер не подтвердил авторизацию".into()),
+        }
+
+        let session = match &self.noise_keys {
+            Some(keys) => Some(
+                noise::initiate(&mut sink, &mut stream, keys, self.config.noise_peer_keys())
+                    .await?,
+            ),
+            None => None,
+        };
+
+        Ok(Established {
+            sink,
+            stream,
+            session,
+            capabilities,
+        })
+    }
+
+    // работаем, пока собеседник не закроет соединение
+    async fn serve(&mut self, established: Established) {
+        let Established {
+            mut sink,
+            mut stream,
+            session,
+            capabilities,
+        } = established;
+
+        // куски наших файлов, которые собеседник попросил прислать
+        let (chunk_tx, mut chunk_rx) = mpsc::channel::<Vec<u8>>(transfer::CHUNK_QUEUE);
+        // кто в сети по этому соединению — при обрыве все они для нас уходят
+        let online: Arc<Mutex<HashMap<String, usize>>> = Arc::default();
 
         // старт задачи получения сообщений
         let user_tx = self.user_tx.clone();
-        let addr = self.config.peer_addr().to_string();
-        let rx_task = tokio::spawn(async move {
+        let mesh = self.mesh.clone();
+        let addr = self.addr.clone();
+        let rx_session = session.clone();
+        let outbox = self.outbox.clone();
+        let rx_online = online.clone();
+        let mut rx_task = tokio::spawn(async move {
             while let Some(Ok(msg)) = stream.next().await {
-                if let Some(text) = msg.as_text() {
-                    if let Ok(message) = serde_json::from_str::<Message>(text) {
-                        if let Err(e) = user_tx.send(message).await {
-                            eprintln!("Ошибка отправки в канал: {}", e);
+                if msg.is_binary() {
+                    match message::open_chunk(msg.as_payload(), rx_session.as_ref()) {
+                        Ok(chunk) => mesh.transfers().receive_chunk(&chunk).await,
+                        Err(e) => {
+                            let notice = format!("Не удалось расшифровать кусок файла: {}", e);
+                            let _ = user_tx.send(NetEvent::Notice(notice)).await;
                         }
                     }
+                    continue;
+                }
+                let Some(text) = msg.as_text() else {
+                    continue;
+                };
+                let event = match message::parse_event(text, rx_session.as_ref()) {
+                    NetEvent::Frame(Frame::Ping | Frame::Pong) => continue,
+                    // ответ на синхронизацию уходит только этому пиру
+                    NetEvent::Frame(frame @ Frame::SyncRequest { .. }) => {
+                        for batch in sync::respond(mesh.history(), &frame) {
+                            outbox.push(batch);
+                        }
+                        continue;
+                    }
+                    NetEvent::Frame(frame @ Frame::SyncBatch { .. }) => {
+                        if let Some(request) = sync::follow_up(mesh.history(), &frame) {
+                            outbox.push(request);
+                        }
+                        for event in sync::backfill(frame) {
+                            let _ = user_tx.send(event).await;
+                        }
+                        continue;
+                    }
+                    NetEvent::Frame(frame @ Frame::FileOffer { .. }) => {
+                        let route = Route::Peer(addr.clone());
+                        mesh.transfers().offered(frame, route).await;
+                        continue;
+                    }
+                    NetEvent::Frame(Frame::FileAccept { id, offset }) => {
+                        mesh.transfers()
+                            .start_upload(&id, offset, &addr, chunk_tx.clone());
+                        continue;
+                    }
+                    NetEvent::Frame(Frame::Presence { username, status }) => {
+                        let mut online = rx_online.lock().unwrap();
+                        match status {
+                            PresenceStatus::Join => {
+                                *online.entry(username.clone()).or_default() += 1
+                            }
+                            PresenceStatus::Leave => {
+                                if let Some(count) = online.get_mut(&username) {
+                                    *count -= 1;
+                                    if *count == 0 {
+                                        online.remove(&username);
+                                    }
+                                }
+                            }
+                            PresenceStatus::Away | PresenceStatus::Back => {}
+                        }
+                        NetEvent::Frame(Frame::Presence { username, status })
+                    }
+                    // чужие личные сообщения не показываем и дальше не передаём
+                    NetEvent::Frame(Frame::Direct(message))
+                        if message.to != Some(mesh.username()) =>
+                    {
+                        continue;
+                    }
+                    NetEvent::Frame(Frame::Typing { to: Some(to), .. })
+                        if to != mesh.username() =>
+                    {
+                        continue;
+                    }
+                    event => event,
+                };
+                if !mesh.accept(&event, Origin::Peer(&addr)) {
+                    continue;
+                }
+                if user_tx.send(event).await.is_err() {
+                    break;
                 }
             }
-            eprintln!("Соединение с {} закрыто", addr);
         });
 
-        // обработка отправки сообщений
-        while let Some(mut message) = self.net_rx.recv().await {
-            message.token = token.clone();
-            message::send_message(&mut sink, &message).await;
+        // сначала узнаём, чего мы не застали, пока были не на связи, и просим
+        // дослать файлы, загрузка которых оборвалась вместе с прошлым соединением
+        let mut requests = vec![sync::request(self.mesh.history())];
+        if self.mesh.is_away() {
+            requests.push(Frame::presence(&self.mesh.username(), PresenceStatus::Away));
+        }
+        requests.extend(
+            self.mesh
+                .transfers()
+                .resume(&Route::Peer(self.addr.clone())),
+        );
+        for request in requests {
+            if request.is_supported_by(&capabilities)
+                && message::try_send_secure(&mut sink, &request, session.as_ref())
+                    .await
+                    .is_err()
+            {
+                rx_task.abort();
+                return;
+            }
+        }
+
+        // досылаем очередь по порядку; кадр снимаем только после успешной записи в сокет
+        'serve: loop {
+            while let Some(frame) = self.outbox.front() {
+                // то, чего собеседник не поддерживает, просто снимаем с очереди
+                if frame.is_supported_by(&capabilities) {
+                    match message::try_send_secure(&mut sink, &frame, session.as_ref()).await {
+                        Ok(()) => {}
+                        Err(SendError::Socket(_)) => break 'serve,
+                        // такой кадр не уйдёт и после переподключения — снимаем и сообщаем
+                        Err(e @ SendError::Encrypt(_)) => {
+                            self.outbox.pop_front();
+                            let notice = format!("Кадр для {} не отправлен: {}", self.addr, e);
+                            let _ = self.user_tx.send(NetEvent::Notice(notice)).await;
+                            continue;
+                        }
+                    }
+                }
+                self.outbox.pop_front();
+                if let Frame::Chat(message) | Frame::Direct(message) = &frame {
+                    self.mesh.report_sent(&message.id).await;
+                }
+            }
+            tokio::select! {
+                _ = &mut rx_task => break,
+                _ = self.outbox.wait() => {}
+                Some(chunk) = chunk_rx.recv() => {
+                    if message::try_send_chunk(&mut sink, &chunk, session.as_ref())
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            }
         }
 
         // закрытие соединения
         rx_task.abort();
-        sink.close().await?;
-        Ok(())
+        let _ = sink.close().await;
+        let online = std::mem::take(&mut *online.lock().unwrap());
+        for (username, count) in online {
+            for _ in 0..count {
+                let leave = Frame::presence(&username, PresenceStatus::Leave);
+                let _ = self.user_tx.send(NetEvent::Frame(leave)).await;
+            }
+        }
     }
 }
@@ -0,0 +1,49 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+use rand::Rng;
+
+const BASE_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+// состояние исходящего соединения к одному пиру
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connecting {
+        attempt: u32,
+    },
+    Connected,
+    Backoff {
+        attempt: u32,
+        delay: Duration,
+        error: String,
+    },
+    Failed(String),
+}
+
+// адрес пира -> состояние, публикуется через watch-канал
+pub type PeerStates = BTreeMap<String, ConnectionState>;
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionState::Connecting { attempt } => {
+                write!(f, "подключение (попытка {})", attempt)
+            }
+            ConnectionState::Connected => write!(f, "в сети"),
+            ConnectionState::Backoff { delay, error, .. } => {
+                write!(f, "повтор через {} сек ({})", delay.as_secs(), error)
+            }
+            ConnectionState::Failed(reason) => write!(f, "отключён: {}", reason),
+        }
+    }
+}
+
+// экспонента с потолком; половина задержки случайна, чтобы пиры не ломились одновременно
+pub fn backoff_delay(attempt: u32) -> Duration {
+    let exp = BASE_DELAY.saturating_mul(1 << attempt.saturating_sub(1).min(16));
+    let delay = exp.min(MAX_DELAY);
+    let half = delay / 2;
+    half + half.mul_f64(rand::rng().random::<f64>())
+}
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+use crate::config::SharedConfig;
+use crate::network::NetEvent;
+use crate::network::protocol::PROTOCOL_VERSION;
+
+const MULTICAST_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 42, 99);
+const DISCOVERY_PORT: u16 = 45454;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5);
+// пир пропадает из списка, если долго не объявлялся
+const PEER_TTL: Duration = Duration::from_secs(20);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Announcement {
+    username: String,
+    listen_addr: String,
+    fingerprint: String,
+    version: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredPeer {
+    pub username: String,
+    pub addr: String,
+    pub fingerprint: String,
+    pub version: u32,
+}
+
+// несколько копий чата на одной машине должны делить порт, поэтому сокет собираем через socket2
+fn bind_multicast() -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT).into())?;
+    socket.join_multicast_v4(&MULTICAST_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+// объявляем себя в группе и собираем объявления других узлов
+pub async fn run(config: SharedConfig, fingerprint: String, user_tx: mpsc::Sender<NetEvent>) {
+    let socket = match bind_multicast() {
+        Ok(socket) => socket,
+        Err(e) => {
+            let notice = format!("Обнаружение в сети недоступно: {}", e);
+            let _ = user_tx.send(NetEvent::Notice(notice)).await;
+            return;
+        }
+    };
+
+    let announcement = serde_json::to_vec(&Announcement {
+        username: config.username().to_string(),
+        listen_addr: config.server_addr().to_string(),
+        fingerprint: fingerprint.clone(),
+        version: PROTOCOL_VERSION,
+    })
+    .unwrap();
+    let group = SocketAddrV4::new(MULTICAST_GROUP, DISCOVERY_PORT);
+
+    let mut peers: HashMap<String, (DiscoveredPeer, Instant)> = HashMap::new();
+    let mut interval = tokio::time::interval(ANNOUNCE_INTERVAL);
+    let mut buf = [0u8; 1024];
+    // без маршрута до группы отправка падает каждые ANNOUNCE_INTERVAL — сообщаем один раз
+    let mut send_failed = false;
+
+    loop {
+        let changed = tokio::select! {
+            _ = interval.tick() => {
+                match socket.send_to(&announcement, group).await {
+                    Ok(_) => send_failed = false,
+                    Err(e) if !send_failed => {
+                        send_failed = true;
+                        let notice = format!("Ошибка отправки объявления: {}", e);
+                        let _ = user_tx.send(NetEvent::Notice(notice)).await;
+                    }
+                    Err(_) => {}
+                }
+                let before = peers.len();
+                peers.retain(|_, (_, last_seen)| last_seen.elapsed() < PEER_TTL);
+                peers.len() != before
+            }
+            received = socket.recv_from(&mut buf) => {
+                let Ok((len, source)) = received else {
+                    continue;
+                };
+                match serde_json::from_slice::<Announcement>(&buf[..len]) {
+                    Ok(announcement) if announcement.fingerprint != fingerprint => {
+                        let peer = discovered_peer(announcement, source);
+                        let changed = peers
+                            .get(&peer.fingerprint)
+                            .is_none_or(|(known, _)| *known != peer);
+                        peers.insert(peer.fingerprint.clone(), (peer, Instant::now()));
+                        changed
+                    }
+                    _ => false,
+                }
+            }
+        };
+
+        if changed {
+            let mut list: Vec<DiscoveredPeer> =
+                peers.values().map(|(peer, _)| peer.clone()).collect();
+            list.sort_by(|a, b| a.username.cmp(&b.username));
+            if user_tx.send(NetEvent::Discovered(list)).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// если сервер слушает 0.0.0.0, подставляем адрес, с которого пришло объявление
+fn discovered_peer(announcement: Announcement, source: SocketAddr) -> DiscoveredPeer {
+    let addr = match announcement.listen_addr.parse::<SocketAddr>() {
+        Ok(listen) if listen.ip().is_unspecified() => {
+            SocketAddr::new(source.ip(), listen.port()).to_string()
+        }
+        _ => announcement.listen_addr,
+    };
+
+    DiscoveredPeer {
+        username: announcement.username,
+        addr,
+        fingerprint: announcement.fingerprint,
+        version: announcement.version,
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::mpsc;
+
+use crate::network::NetEvent;
+use crate::network::protocol::Frame;
+
+pub type ConnectionId = u64;
+
+// реестр подключённых клиентов для режима хаба
+#[derive(Clone)]
+pub struct Hub {
+    inner: Arc<Mutex<HubInner>>,
+    user_tx: mpsc::Sender<NetEvent>,
+}
+
+#[derive(Default)]
+struct HubInner {
+    next_id: ConnectionId,
+    connections: HashMap<ConnectionId, mpsc::Sender<Frame>>,
+    // клиенты, которым кадр не ушёл: о каждом сообщаем один раз, пока он не догонит
+    lagging: HashSet<ConnectionId>,
+}
+
+impl Hub {
+    pub fn new(user_tx: mpsc::Sender<NetEvent>) -> Self {
+        Self {
+            inner: Arc::default(),
+            user_tx,
+        }
+    }
+
+    pub fn register(&self, tx: mpsc::Sender<Frame>) -> ConnectionId {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        let id = inner.next_id;
+        inner.connections.insert(id, tx);
+        id
+    }
+
+    pub fn unregister(&self, id: ConnectionId) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.connections.remove(&id);
+        inner.lagging.remove(&id);
+    }
+
+    // рассылаем всем, кроме источника; except = None — кадр от локального пользователя
+    // возвращает число клиентов, которым кадр передан
+    pub fn broadcast(&self, frame: &Frame, except: Option<ConnectionId>) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let HubInner {
+            connections,
+            lagging,
+            ..
+        } = &mut *inner;
+        let mut delivered = 0;
+        for (id, tx) in connections.iter() {
+            if Some(*id) == except {
+                continue;
+            }
+            match tx.try_send(frame.clone()) {
+                Ok(()) => {
+                    delivered += 1;
+                    lagging.remove(id);
+                }
+                Err(e) if lagging.insert(*id) => {
+                    let notice = format!("Не удалось переслать кадр клиенту {}: {}", id, e);
+                    let _ = self.user_tx.try_send(NetEvent::Notice(notice));
+                }
+                Err(_) => {}
+            }
+        }
+        delivered
+    }
+}
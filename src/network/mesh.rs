@@ -0,0 +1,554 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{mpsc, watch};
+
+use crate::config::SharedConfig;
+use crate::identity::{self, Identity, SignatureStatus};
+use crate::models::message::{DeliveryState, Message};
+use crate::network::client::PeerClient;
+use crate::network::connection::{ConnectionState, PeerStates};
+use crate::network::hub::{ConnectionId, Hub};
+use crate::network::noise::NoiseKeys;
+use crate::network::outbox::Outbox;
+use crate::network::protocol::{Frame, PresenceStatus};
+use crate::network::transfer::{Route, Transfers};
+use crate::network::{NetCommand, NetEvent};
+use crate::storage::history::SharedHistory;
+
+pub const MAX_HOPS: u8 = 8;
+const SEEN_CAPACITY: usize = 4096;
+
+// откуда пришёл кадр — чтобы не отправлять его обратно источнику
+#[derive(Debug, Clone, Copy)]
+pub enum Origin<'a> {
+    Local,
+    Peer(&'a str),
+    Inbound(Option<ConnectionId>),
+}
+
+// помним последние id, чтобы не показывать и не пересылать одно сообщение дважды
+#[derive(Default)]
+struct SeenCache {
+    ids: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl SeenCache {
+    // true, если id встретился впервые
+    fn insert(&mut self, id: &str) -> bool {
+        if !self.ids.insert(id.to_string()) {
+            return false;
+        }
+        self.order.push_back(id.to_string());
+        if self.order.len() > SEEN_CAPACITY
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.ids.remove(&oldest);
+        }
+        true
+    }
+}
+
+// кто на том конце исходящего соединения — узнаём из welcome
+#[derive(Debug, Clone, Default)]
+pub struct RemotePeer {
+    pub username: Option<String>,
+    pub hub: bool,
+}
+
+// входящее соединение и ключ, которым клиент подписал вход; без ключа личное туда не шлём
+struct InboundRoute {
+    tx: mpsc::Sender<Frame>,
+    public_key: Option<String>,
+}
+
+#[derive(Default)]
+struct MeshInner {
+    // имя из настроек, пока его не сменили через /nick
+    username: String,
+    peers: HashMap<String, Outbox>,
+    remote: HashMap<String, RemotePeer>,
+    // входящие соединения по имени пользователя — для адресных кадров вроде file_accept
+    inbound: HashMap<String, InboundRoute>,
+    // кто из подключившихся к нам отошёл, и отошли ли мы сами
+    away: HashSet<String>,
+    local_away: bool,
+    seen: SeenCache,
+}
+
+// сетевой контекст узла: исходящие соединения ко всем пирам, госсип и дедупликация
+#[derive(Clone)]
+pub struct Mesh {
+    config: SharedConfig,
+    user_tx: mpsc::Sender<NetEvent>,
+    noise_keys: Option<Arc<NoiseKeys>>,
+    hub: Option<Hub>,
+    history: SharedHistory,
+    identity: Arc<Identity>,
+    transfers: Transfers,
+    inner: Arc<Mutex<MeshInner>>,
+    states: watch::Sender<PeerStates>,
+}
+
+impl Mesh {
+    pub fn new(
+        config: SharedConfig,
+        user_tx: mpsc::Sender<NetEvent>,
+        noise_keys: Option<Arc<NoiseKeys>>,
+        hub: Option<Hub>,
+        history: SharedHistory,
+        identity: Arc<Identity>,
+    ) -> Self {
+        let transfers = Transfers::new(config.download_dir().to_path_buf(), user_tx.clone());
+        let inner = MeshInner {
+            username: config.username().to_string(),
+            ..MeshInner::default()
+        };
+        Self {
+            config,
+            user_tx,
+            noise_keys,
+            hub,
+            history,
+            identity,
+            transfers,
+            inner: Arc::new(Mutex::new(inner)),
+            states: watch::Sender::new(PeerStates::new()),
+        }
+    }
+
+    pub fn config(&self) -> &SharedConfig {
+        &self.config
+    }
+
+    pub fn user_tx(&self) -> &mpsc::Sender<NetEvent> {
+        &self.user_tx
+    }
+
+    pub fn noise_keys(&self) -> Option<&Arc<NoiseKeys>> {
+        self.noise_keys.as_ref()
+    }
+
+    pub fn hub(&self) -> Option<&Hub> {
+        self.hub.as_ref()
+    }
+
+    pub fn history(&self) -> &SharedHistory {
+        &self.history
+    }
+
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    pub fn transfers(&self) -> &Transfers {
+        &self.transfers
+    }
+
+    pub fn username(&self) -> String {
+        self.inner.lock().unwrap().username.clone()
+    }
+
+    pub fn subscribe_states(&self) -> watch::Receiver<PeerStates> {
+        self.states.subscribe()
+    }
+
+    pub fn set_state(&self, addr: &str, state: ConnectionState) {
+        self.states.send_modify(|states| {
+            states.insert(addr.to_string(), state);
+        });
+    }
+
+    // клиент сдался — убираем пира, чтобы к нему можно было подключиться заново
+    pub fn forget(&self, addr: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.peers.remove(addr);
+        inner.remote.remove(addr);
+    }
+
+    pub fn set_remote(&self, addr: &str, remote: RemotePeer) {
+        self.inner
+            .lock()
+            .unwrap()
+            .remote
+            .insert(addr.to_string(), remote);
+    }
+
+    // поднимаем исходящее соединение к пиру, если его ещё нет; false — уже есть
+    pub fn dial(&self, addr: &str) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.peers.contains_key(addr) {
+            return false;
+        }
+
+        let outbox = self.open_outbox(addr);
+        inner.peers.insert(addr.to_string(), outbox.clone());
+
+        let mut client = PeerClient::new(self.clone(), addr.to_string(), outbox);
+        tokio::spawn(async move { client.run().await });
+        true
+    }
+
+    // занятое имя может забрать только тот же ключ — это переподключение, а не самозванец
+    pub fn can_register_inbound(&self, username: &str, public_key: Option<&str>) -> bool {
+        self.inner
+            .lock()
+            .unwrap()
+            .inbound
+            .get(username)
+            .is_none_or(|current| {
+                public_key.is_some() && current.public_key.as_deref() == public_key
+            })
+    }
+
+    pub fn register_inbound(
+        &self,
+        username: &str,
+        public_key: Option<String>,
+        tx: mpsc::Sender<Frame>,
+    ) -> Result<(), String> {
+        if !self.can_register_inbound(username, public_key.as_deref()) {
+            return Err(format!("Имя {} уже занято другим участником", username));
+        }
+        self.inner
+            .lock()
+            .unwrap()
+            .inbound
+            .insert(username.to_string(), InboundRoute { tx, public_key });
+        Ok(())
+    }
+
+    // пользователь мог уже переподключиться — убираем только своё соединение
+    pub fn unregister_inbound(&self, username: &str, tx: &mpsc::Sender<Frame>) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner
+            .inbound
+            .get(username)
+            .is_some_and(|current| current.tx.same_channel(tx))
+        {
+            inner.inbound.remove(username);
+        }
+    }
+
+    pub fn is_away(&self) -> bool {
+        self.inner.lock().unwrap().local_away
+    }
+
+    pub fn set_away(&self, username: &str, away: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        if away {
+            inner.away.insert(username.to_string());
+        } else {
+            inner.away.remove(username);
+        }
+    }
+
+    // кто уже в сети — новому соединению: мы сами, а у хаба ещё и остальные его клиенты
+    pub fn roster(&self, except: &str) -> Vec<Frame> {
+        let inner = self.inner.lock().unwrap();
+        let mut roster = vec![Frame::presence(&inner.username, PresenceStatus::Join)];
+        if inner.local_away {
+            roster.push(Frame::presence(&inner.username, PresenceStatus::Away));
+        }
+        if self.hub.is_some() {
+            for username in inner.inbound.keys().filter(|username| *username != except) {
+                roster.push(Frame::presence(username, PresenceStatus::Join));
+                if inner.away.contains(username) {
+                    roster.push(Frame::presence(username, PresenceStatus::Away));
+                }
+            }
+        }
+        roster
+    }
+
+    // свой статус — всем соседям: и тем, к кому подключились мы, и тем, кто к нам
+    fn announce(&self, frame: Frame) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Frame::Presence { status, .. } = &frame {
+            inner.local_away = *status == PresenceStatus::Away;
+        }
+        // набор текста устаревает за секунды — в очереди отключённых пиров его не кладём
+        let ephemeral = matches!(frame, Frame::Typing { .. });
+        for route in inner.inbound.values() {
+            let _ = route.tx.try_send(frame.clone());
+        }
+        for (addr, outbox) in inner.peers.iter() {
+            if !ephemeral || self.is_connected(addr) {
+                outbox.push(frame.clone());
+            }
+        }
+    }
+
+    fn is_connected(&self, addr: &str) -> bool {
+        matches!(
+            self.states.borrow().get(addr),
+            Some(ConnectionState::Connected)
+        )
+    }
+
+    // набор текста в личной переписке видит только адресат
+    pub fn send_typing(&self, to: &str, frame: Frame) {
+        let Some(route) = self.direct_route(to) else {
+            return;
+        };
+        let connected = match &route {
+            Route::Peer(addr) => self.is_connected(addr),
+            Route::Inbound(_) => true,
+        };
+        if connected {
+            self.send_to(&route, frame);
+        }
+    }
+
+    // кадр только одному собеседнику; false — соединения с ним сейчас нет
+    fn send_to(&self, route: &Route, frame: Frame) -> bool {
+        let inner = self.inner.lock().unwrap();
+        match route {
+            Route::Peer(addr) => match inner.peers.get(addr) {
+                Some(outbox) => {
+                    outbox.push(frame);
+                    true
+                }
+                None => false,
+            },
+            Route::Inbound(username) => inner
+                .inbound
+                .get(username)
+                .is_some_and(|route| route.tx.try_send(frame).is_ok()),
+        }
+    }
+
+    // адресат среди входящих (только подтвердивших ключ), затем среди исходящих,
+    // а если его нигде нет — через хаб
+    fn direct_route(&self, to: &str) -> Option<Route> {
+        let inner = self.inner.lock().unwrap();
+        if inner
+            .inbound
+            .get(to)
+            .is_some_and(|route| route.public_key.is_some())
+        {
+            return Some(Route::Inbound(to.to_string()));
+        }
+        let mut connected = inner
+            .remote
+            .iter()
+            .filter(|(addr, _)| inner.peers.contains_key(*addr));
+        let by_name = connected
+            .clone()
+            .find(|(_, peer)| peer.username.as_deref() == Some(to));
+        by_name
+            .or_else(|| connected.find(|(_, peer)| peer.hub))
+            .map(|(addr, _)| Route::Peer(addr.clone()))
+    }
+
+    // личное сообщение уходит одному соединению; false — пути до адресата нет
+    pub async fn send_direct(&self, message: Message) -> bool {
+        let Some(route) = message.to.as_deref().and_then(|to| self.direct_route(to)) else {
+            return false;
+        };
+        let id = message.id.clone();
+        if !self.send_to(&route, Frame::Direct(message)) {
+            return false;
+        }
+        // во входящее соединение кадр пишется сразу, исходящие отчитаются сами
+        if let Route::Inbound(_) = route {
+            self.report_sent(&id).await;
+        }
+        true
+    }
+
+    // хаб передаёт чужое личное сообщение дальше; счётчик не даёт ему кружить между хабами
+    pub async fn forward_direct(&self, mut message: Message) -> bool {
+        if message.hops >= MAX_HOPS {
+            return false;
+        }
+        message.hops += 1;
+        self.send_direct(message).await
+    }
+
+    fn open_outbox(&self, addr: &str) -> Outbox {
+        if !self.config.persist_outbox() {
+            return Outbox::in_memory();
+        }
+        match Outbox::open(&self.config.config_dir().join("outbox"), addr) {
+            Ok(outbox) => outbox,
+            Err(e) => {
+                let notice = format!("Очередь для {} будет только в памяти: {}", addr, e);
+                let _ = self.user_tx.try_send(NetEvent::Notice(notice));
+                Outbox::in_memory()
+            }
+        }
+    }
+
+    // кадры не теряются при обрыве: клиент дошлёт их из очереди после переподключения
+    fn send_to_peers(&self, frame: &Frame, except: Option<&str>) {
+        let inner = self.inner.lock().unwrap();
+        for (addr, outbox) in inner.peers.iter() {
+            if Some(addr.as_str()) != except {
+                outbox.push(frame.clone());
+            }
+        }
+    }
+
+    fn mark_seen(&self, id: &str) -> bool {
+        self.inner.lock().unwrap().seen.insert(id)
+    }
+
+    // false — дубликат, показывать не нужно; новые кадры заодно расходятся дальше
+    pub fn accept(&self, event: &NetEvent, origin: Origin<'_>) -> bool {
+        let NetEvent::Frame(frame) = event else {
+            return true;
+        };
+        if !frame.is_relayable() {
+            return true;
+        }
+        // поддельный кадр не должен ни расходиться, ни занимать место настоящего в кэше
+        if forged(frame) {
+            return false;
+        }
+        if let Some(key) = frame.dedup_key()
+            && !self.mark_seen(&key)
+        {
+            return false;
+        }
+
+        let (from_peer, from_connection) = match origin {
+            Origin::Local => (None, None),
+            Origin::Peer(addr) => (Some(addr), None),
+            Origin::Inbound(id) => (None, id),
+        };
+
+        if let Some(hub) = &self.hub {
+            hub.broadcast(frame, from_connection);
+        }
+
+        // квитанции ходят без счётчика: петли обрывает дедупликация
+        match frame {
+            Frame::Chat(message) if message.hops < MAX_HOPS => {
+                let mut forwarded = message.clone();
+                forwarded.hops += 1;
+                self.send_to_peers(&Frame::Chat(forwarded), from_peer);
+            }
+            Frame::Chat(_) => {}
+            _ => self.send_to_peers(frame, from_peer),
+        }
+
+        true
+    }
+
+    // команды из уи: исходящие кадры уходят всем пирам и клиентам хаба
+    pub async fn relay_local(self, mut net_rx: mpsc::Receiver<NetCommand>) {
+        while let Some(command) = net_rx.recv().await {
+            match command {
+                NetCommand::Send(frame) => self.relay_frame(*frame).await,
+                NetCommand::SendFile(path) => {
+                    tokio::spawn(self.clone().offer_file(path));
+                }
+                NetCommand::AcceptFile(id) => self.accept_file(&id).await,
+                // соседи узнают новое имя из hello при следующем подключении
+                NetCommand::Rename(name) => self.inner.lock().unwrap().username = name,
+                NetCommand::Dial(addr) => {
+                    let notice = if self.dial(&addr) {
+                        format!("Подключаемся к {}", addr)
+                    } else {
+                        format!("К {} уже есть соединение", addr)
+                    };
+                    let _ = self.user_tx.send(NetEvent::Notice(notice)).await;
+                }
+            }
+        }
+    }
+
+    async fn relay_frame(&self, frame: Frame) {
+        if let Frame::Typing { to: Some(to), .. } = &frame {
+            let to = to.clone();
+            self.send_typing(&to, frame);
+            return;
+        }
+        if let Frame::Presence { .. } | Frame::Typing { .. } = frame {
+            self.announce(frame);
+            return;
+        }
+        if let Frame::Direct(message) = frame {
+            let to = message.to.clone().unwrap_or_default();
+            if !self.send_direct(message).await {
+                let notice = format!("{} не в сети, личное сообщение не отправлено", to);
+                let _ = self.user_tx.send(NetEvent::Notice(notice)).await;
+            }
+            return;
+        }
+        if let Some(key) = frame.dedup_key() {
+            self.mark_seen(&key);
+        }
+        // клиентам хаба кадр уходит сразу, очереди у них нет
+        if let Some(hub) = &self.hub
+            && hub.broadcast(&frame, None) > 0
+            && let Frame::Chat(message) = &frame
+        {
+            self.report_sent(&message.id).await;
+        }
+        self.send_to_peers(&frame, None);
+    }
+
+    // хешируем файл в фоне, чтобы не задерживать остальные команды
+    async fn offer_file(self, path: PathBuf) {
+        match self.transfers.offer(path, &self.username()).await {
+            Ok(frame) => self.relay_frame(frame).await,
+            Err(e) => {
+                let notice = format!("Не удалось предложить файл: {}", e);
+                let _ = self.user_tx.send(NetEvent::Notice(notice)).await;
+            }
+        }
+    }
+
+    async fn accept_file(&self, id: &str) {
+        let notice = match self.transfers.accept(id).await {
+            Ok(Some((route, frame))) => {
+                if self.send_to(&route, frame) {
+                    return;
+                }
+                format!(
+                    "Нет соединения с {}, загрузка начнётся после переподключения",
+                    route.label()
+                )
+            }
+            Ok(None) => return,
+            Err(e) => format!("Не удалось принять файл: {}", e),
+        };
+        let _ = self.user_tx.send(NetEvent::Notice(notice)).await;
+    }
+
+    pub async fn report_sent(&self, id: &str) {
+        let event = NetEvent::Delivery {
+            id: id.to_string(),
+            state: DeliveryState::Sent,
+        };
+        let _ = self.user_tx.send(event).await;
+    }
+}
+
+// подписанные кадры проверяем до дедупликации; неподписанный чат пропускаем — его пометит уи
+fn forged(frame: &Frame) -> bool {
+    match frame {
+        Frame::Chat(message) => identity::verify_message(message) == SignatureStatus::Invalid,
+        Frame::Edit(message) => identity::verify_message(message) != SignatureStatus::Valid,
+        Frame::Delete {
+            id,
+            at,
+            public_key,
+            signature,
+        } => !identity::verify_deletion(id, *at, public_key, signature),
+        Frame::Reaction {
+            id,
+            emoji,
+            on,
+            at,
+            public_key,
+            signature,
+            ..
+        } => !identity::verify_reaction(id, emoji, *on, *at, public_key, signature),
+        _ => false,
+    }
+}
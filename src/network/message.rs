@@ -2,44 +2,131 @@ use futures_util::{
     SinkExt, StreamExt,
     stream::{SplitSink, SplitStream},
 };
-use serde_json;
-use tokio::net::TcpStream;
-use tokio_websockets::{MaybeTlsStream, Message as WsMessage, WebSocketStream};
-
-use crate::models::message::Message;
-
-pub async fn send_message(
-    sink: &mut SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, WsMessage>,
-    msg: &Message,
-) {
-    let serialized = serde_json::to_string(msg).unwrap();
-    if let Err(e) = sink.send(WsMessage::text(serialized)).await {
-        eprintln!("Ошибка отправки сообщения: {}", e);
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_websockets::{Message as WsMessage, WebSocketStream};
+
+use crate::network::NetEvent;
+use crate::network::noise::NoiseSession;
+use crate::network::protocol::Frame;
+
+// половинки сокета; S — tcp или tls поток, в зависимости от настроек
+pub type WsSink<S> = SplitSink<WebSocketStream<S>, WsMessage>;
+pub type WsStream<S> = SplitStream<WebSocketStream<S>>;
+
+// открытый кадр на этапе рукопожатия; ошибку решает вызывающий
+pub async fn send_frame<S>(
+    sink: &mut WsSink<S>,
+    frame: &Frame,
+) -> Result<(), tokio_websockets::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    sink.send(WsMessage::text(frame.encode())).await
+}
+
+// кадр не зашифровался — повторять бесполезно, его надо выбросить; сокет сломался —
+// кадр остаётся в очереди до переподключения
+#[derive(Debug)]
+pub enum SendError {
+    Encrypt(Box<dyn std::error::Error + Send + Sync>),
+    Socket(tokio_websockets::Error),
+}
+
+impl std::fmt::Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendError::Encrypt(e) => write!(f, "не удалось зашифровать кадр: {}", e),
+            SendError::Socket(e) => write!(f, "ошибка отправки: {}", e),
+        }
     }
 }
 
-pub async fn receive_messages(
-    mut stream: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
-    mut sink: SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, WsMessage>,
-    receiver_tx: tokio::sync::mpsc::Sender<Message>,
-) {
+impl std::error::Error for SendError {}
+
+// после Noise-рукопожатия всё, кроме ping/pong, уходит внутри encrypted;
+// ошибку отдаём вызывающему — чтобы не потерять кадр из очереди
+pub async fn try_send_secure<S>(
+    sink: &mut WsSink<S>,
+    frame: &Frame,
+    session: Option<&NoiseSession>,
+) -> Result<(), SendError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let frame = match session {
+        Some(session) => session.encrypt(frame).map_err(SendError::Encrypt)?,
+        None => frame.clone(),
+    };
+    sink.send(WsMessage::text(frame.encode()))
+        .await
+        .map_err(SendError::Socket)?;
+    Ok(())
+}
+
+// кусок файла уходит бинарным кадром; с Noise шифруется так же, как текстовые
+pub async fn try_send_chunk<S>(
+    sink: &mut WsSink<S>,
+    chunk: &[u8],
+    session: Option<&NoiseSession>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let payload = match session {
+        Some(session) => session.encrypt_bytes(chunk)?,
+        None => chunk.to_vec(),
+    };
+    sink.send(WsMessage::binary(payload)).await?;
+    Ok(())
+}
+
+pub fn open_chunk(
+    payload: &[u8],
+    session: Option<&NoiseSession>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    match session {
+        Some(session) => session.decrypt_bytes(payload),
+        None => Ok(payload.to_vec()),
+    }
+}
+
+// ждём следующий текстовый кадр, используется на этапе рукопожатия
+pub async fn read_frame<S>(stream: &mut WsStream<S>) -> Option<Frame>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     while let Some(Ok(msg)) = stream.next().await {
         if let Some(text) = msg.as_text() {
-            if let Ok(message) = serde_json::from_str::<Message>(text) {
-                if let Err(e) = receiver_tx.send(message).await {
-                    eprintln!("Ошибка отправки сообщения в канал: {}", e);
-                }
-            }
+            return Frame::decode(text).ok();
         }
     }
+    None
+}
 
-    handle_connection_close(&mut sink).await;
+// неизвестные и битые кадры не выкидываем, а показываем пользователю
+fn decode_event(text: &str) -> NetEvent {
+    match Frame::decode(text) {
+        Ok(frame) => NetEvent::Frame(frame),
+        Err(e) => NetEvent::Notice(e.to_string()),
+    }
 }
 
-async fn handle_connection_close(
-    sink: &mut SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, WsMessage>,
-) {
-    if let Err(e) = sink.close().await {
-        eprintln!("Ошибка при закрытии соединения: {}", e);
+pub fn parse_event(text: &str, session: Option<&NoiseSession>) -> NetEvent {
+    match (decode_event(text), session) {
+        (NetEvent::Frame(Frame::Encrypted { payload }), Some(session)) => {
+            match session.decrypt(&payload) {
+                Ok(plain) => decode_event(&plain),
+                Err(e) => NetEvent::Notice(format!("Не удалось расшифровать кадр: {}", e)),
+            }
+        }
+        (NetEvent::Frame(Frame::Encrypted { .. }), None) => {
+            NetEvent::Notice("Получен зашифрованный кадр, но Noise не включён".to_string())
+        }
+        (event @ NetEvent::Frame(Frame::Ping | Frame::Pong), _) => event,
+        // открытый текст внутри защищённой сессии — попытка понижения, не принимаем
+        (NetEvent::Frame(_), Some(_)) => {
+            NetEvent::Notice("Отклонён незашифрованный кадр".to_string())
+        }
+        (event, _) => event,
     }
 }
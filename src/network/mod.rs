@@ -1,3 +1,44 @@
+pub mod auth;
 pub mod client;
+pub mod connection;
+pub mod discovery;
+pub mod hub;
+pub mod mesh;
 pub mod message;
+pub mod noise;
+pub mod outbox;
+pub mod protocol;
 pub mod server;
+pub mod sync;
+pub mod tls;
+pub mod transfer;
+
+use std::path::PathBuf;
+
+use crate::models::message::{DeliveryState, Message};
+use discovery::DiscoveredPeer;
+use protocol::Frame;
+use transfer::TransferProgress;
+
+// то, что сетевой слой отдаёт в уи
+#[derive(Debug, Clone)]
+pub enum NetEvent {
+    Frame(Frame),
+    // сообщение, досланное синхронизацией: квитанций на него не шлём
+    Backfill(Box<Message>),
+    Notice(String),
+    Discovered(Vec<DiscoveredPeer>),
+    Delivery { id: String, state: DeliveryState },
+    Transfer(TransferProgress),
+}
+
+// то, что уи просит у сетевого слоя
+#[derive(Debug, Clone)]
+pub enum NetCommand {
+    Send(Box<Frame>),
+    Dial(String),
+    // /nick: новое имя для рукопожатий и адресных сообщений
+    Rename(String),
+    SendFile(PathBuf),
+    AcceptFile(String),
+}
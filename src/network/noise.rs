@@ -0,0 +1,218 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use snow::{Builder, HandshakeState, TransportState};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::network::message::{self, WsSink, WsStream};
+use crate::network::protocol::Frame;
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+const MAX_NOISE_MESSAGE: usize = 65535;
+// 16 байт занимает тег ChaChaPoly
+const MAX_NOISE_PLAINTEXT: usize = MAX_NOISE_MESSAGE - 16;
+// куски длинного кадра идут в одном payload через точку — в base64 её не бывает
+const PIECE_SEPARATOR: char = '.';
+
+type NoiseResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+pub struct NoiseKeys {
+    private: Vec<u8>,
+    pub public: Vec<u8>,
+}
+
+impl NoiseKeys {
+    pub fn public_hex(&self) -> String {
+        hex::encode(&self.public)
+    }
+}
+
+// статический ключ живёт в файле: приватный и публичный hex, по строке на каждый
+pub fn load_or_generate_keys(path: &Path) -> NoiseResult<NoiseKeys> {
+    if path.exists() {
+        let content = std::fs::read_to_string(path)?;
+        let mut lines = content.lines();
+        let (Some(private), Some(public)) = (lines.next(), lines.next()) else {
+            return Err(format!("повреждён файл ключа {}", path.display()).into());
+        };
+        return Ok(NoiseKeys {
+            private: hex::decode(private.trim())?,
+            public: hex::decode(public.trim())?,
+        });
+    }
+
+    let keypair = Builder::new(NOISE_PARAMS.parse()?).generate_keypair()?;
+    let content = format!(
+        "{}\n{}\n",
+        hex::encode(&keypair.private),
+        hex::encode(&keypair.public)
+    );
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, content)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    }
+
+    Ok(NoiseKeys {
+        private: keypair.private,
+        public: keypair.public,
+    })
+}
+
+// зашифрованный канал после рукопожатия; клонируется между задачами чтения и записи
+#[derive(Clone)]
+pub struct NoiseSession {
+    transport: Arc<Mutex<TransportState>>,
+    remote_key: String,
+}
+
+impl NoiseSession {
+    pub fn remote_key(&self) -> &str {
+        &self.remote_key
+    }
+
+    // кадр больше одного Noise-сообщения режем на куски; короткий выглядит как раньше
+    pub fn encrypt(&self, frame: &Frame) -> NoiseResult<Frame> {
+        let encoded = frame.encode();
+        // куски одного кадра шифруем подряд, чтобы их номера не перемешались с чужими
+        let mut transport = self.transport.lock().unwrap();
+        let mut pieces = Vec::new();
+        for plain in encoded.as_bytes().chunks(MAX_NOISE_PLAINTEXT) {
+            let mut buf = vec![0u8; MAX_NOISE_MESSAGE];
+            let len = transport.write_message(plain, &mut buf)?;
+            pieces.push(BASE64.encode(&buf[..len]));
+        }
+        Ok(Frame::Encrypted {
+            payload: pieces.join(&PIECE_SEPARATOR.to_string()),
+        })
+    }
+
+    pub fn decrypt(&self, payload: &str) -> NoiseResult<String> {
+        let mut plain = Vec::new();
+        for piece in payload.split(PIECE_SEPARATOR) {
+            plain.extend(self.decrypt_bytes(&BASE64.decode(piece)?)?);
+        }
+        Ok(String::from_utf8(plain)?)
+    }
+
+    // бинарные кадры (куски файлов) шифруем как есть, без base64
+    pub fn encrypt_bytes(&self, plain: &[u8]) -> NoiseResult<Vec<u8>> {
+        let mut buf = vec![0u8; MAX_NOISE_MESSAGE];
+        let len = self
+            .transport
+            .lock()
+            .unwrap()
+            .write_message(plain, &mut buf)?;
+        buf.truncate(len);
+        Ok(buf)
+    }
+
+    pub fn decrypt_bytes(&self, ciphertext: &[u8]) -> NoiseResult<Vec<u8>> {
+        let mut buf = vec![0u8; MAX_NOISE_MESSAGE];
+        let len = self
+            .transport
+            .lock()
+            .unwrap()
+            .read_message(ciphertext, &mut buf)?;
+        buf.truncate(len);
+        Ok(buf)
+    }
+}
+
+// XX: -> e; <- e, ee, s, es; -> s, se
+pub async fn initiate<S>(
+    sink: &mut WsSink<S>,
+    stream: &mut WsStream<S>,
+    keys: &NoiseKeys,
+    expected_keys: &[String],
+) -> NoiseResult<NoiseSession>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut handshake = Builder::new(NOISE_PARAMS.parse()?)
+        .local_private_key(&keys.private)
+        .build_initiator()?;
+
+    write_handshake(sink, &mut handshake).await?;
+    read_handshake(stream, &mut handshake).await?;
+    write_handshake(sink, &mut handshake).await?;
+
+    finish(handshake, expected_keys)
+}
+
+pub async fn respond<S>(
+    sink: &mut WsSink<S>,
+    stream: &mut WsStream<S>,
+    keys: &NoiseKeys,
+    expected_keys: &[String],
+) -> NoiseResult<NoiseSession>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut handshake = Builder::new(NOISE_PARAMS.parse()?)
+        .local_private_key(&keys.private)
+        .build_responder()?;
+
+    read_handshake(stream, &mut handshake).await?;
+    write_handshake(sink, &mut handshake).await?;
+    read_handshake(stream, &mut handshake).await?;
+
+    finish(handshake, expected_keys)
+}
+
+async fn write_handshake<S>(sink: &mut WsSink<S>, handshake: &mut HandshakeState) -> NoiseResult<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; MAX_NOISE_MESSAGE];
+    let len = handshake.write_message(&[], &mut buf)?;
+    let frame = Frame::Noise {
+        payload: BASE64.encode(&buf[..len]),
+    };
+    message::send_frame(sink, &frame).await?;
+    Ok(())
+}
+
+async fn read_handshake<S>(
+    stream: &mut WsStream<S>,
+    handshake: &mut HandshakeState,
+) -> NoiseResult<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let payload = match message::read_frame(stream).await {
+        Some(Frame::Noise { payload }) => payload,
+        Some(Frame::Error { message, .. }) => return Err(message.into()),
+        _ => return Err("собеседник не начал Noise-рукопожатие".into()),
+    };
+    let mut buf = vec![0u8; MAX_NOISE_MESSAGE];
+    handshake.read_message(&BASE64.decode(payload)?, &mut buf)?;
+    Ok(())
+}
+
+// пустой список — принимаем любой ключ, иначе только закреплённые
+fn finish(handshake: HandshakeState, expected_keys: &[String]) -> NoiseResult<NoiseSession> {
+    let remote_key = handshake
+        .get_remote_static()
+        .map(hex::encode)
+        .ok_or("собеседник не прислал статический ключ")?;
+
+    if !expected_keys.is_empty()
+        && !expected_keys
+            .iter()
+            .any(|expected| expected.eq_ignore_ascii_case(&remote_key))
+    {
+        return Err(format!("ключа собеседника {} нет среди ожидаемых", remote_key).into());
+    }
+
+    Ok(NoiseSession {
+        transport: Arc::new(Mutex::new(handshake.into_transport_mode()?)),
+        remote_key,
+    })
+}
@@ -0,0 +1,97 @@
+use std::collections::VecDeque;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+
+use crate::network::protocol::Frame;
+
+// дальше самые старые кадры вытесняются, чтобы очередь к мёртвому пиру не росла бесконечно
+const MAX_QUEUED: usize = 1000;
+
+// очередь исходящих кадров к одному пиру; живёт дольше соединения
+#[derive(Clone)]
+pub struct Outbox {
+    queue: Arc<Mutex<VecDeque<Frame>>>,
+    notify: Arc<Notify>,
+    path: Option<PathBuf>,
+}
+
+impl Outbox {
+    pub fn in_memory() -> Self {
+        Self {
+            queue: Arc::new(Mutex::new(VecDeque::new())),
+            notify: Arc::new(Notify::new()),
+            path: None,
+        }
+    }
+
+    // очередь на диске: по кадру в строке, недоставленное переживает перезапуск
+    pub fn open(dir: &Path, addr: &str) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{}.jsonl", addr.replace([':', '/'], "_")));
+
+        let mut queue = VecDeque::new();
+        if path.exists() {
+            for line in std::fs::read_to_string(&path)?.lines() {
+                if let Ok(frame) = Frame::decode(line) {
+                    queue.push_back(frame);
+                }
+            }
+        }
+
+        let outbox = Self {
+            queue: Arc::new(Mutex::new(queue)),
+            notify: Arc::new(Notify::new()),
+            path: Some(path),
+        };
+        if !outbox.is_empty() {
+            outbox.notify.notify_one();
+        }
+        Ok(outbox)
+    }
+
+    pub fn push(&self, frame: Frame) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.push_back(frame);
+        if queue.len() > MAX_QUEUED {
+            queue.pop_front();
+        }
+        self.persist(&queue);
+        self.notify.notify_one();
+    }
+
+    pub fn front(&self) -> Option<Frame> {
+        self.queue.lock().unwrap().front().cloned()
+    }
+
+    // кадр ушёл в сокет — убираем его из очереди
+    pub fn pop_front(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.pop_front();
+        self.persist(&queue);
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // ждём, пока в очереди что-то появится
+    pub async fn wait(&self) {
+        self.notify.notified().await
+    }
+
+    fn persist(&self, queue: &VecDeque<Frame>) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let content: String = queue.iter().map(|frame| frame.encode() + "\n").collect();
+        // ошибку записи не пробрасываем: очередь в памяти остаётся рабочей
+        let _ = std::fs::write(path, content);
+    }
+}
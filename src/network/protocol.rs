@@ -0,0 +1,398 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::models::message::{DEFAULT_ROOM, Message};
+use crate::storage::history::SyncCursor;
+
+pub const PROTOCOL_VERSION: u32 = 2;
+// самая старая версия, с которой ещё договариваемся
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+// возможности, о которых стороны сообщают друг другу в hello/welcome
+pub const CAP_SYNC: &str = "sync";
+pub const CAP_RECEIPTS: &str = "receipts";
+pub const CAP_NOISE: &str = "noise";
+pub const CAP_FILES: &str = "files";
+pub const CAP_ROOMS: &str = "rooms";
+pub const CAP_DIRECT: &str = "direct";
+pub const CAP_PRESENCE: &str = "presence";
+pub const CAP_TYPING: &str = "typing";
+pub const CAP_EDIT: &str = "edit";
+pub const CAP_REACTIONS: &str = "reactions";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    IncompatibleVersion,
+    MissingCapability,
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceStatus {
+    Join,
+    Leave,
+    Away,
+    Back,
+}
+
+// единый конверт для всего, что ходит по сокету
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Frame {
+    Hello {
+        version: u32,
+        username: String,
+        // у клиентов первой версии этих полей нет
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        min_version: Option<u32>,
+        #[serde(default)]
+        capabilities: Vec<String>,
+    },
+    // ответ сервера на hello: версия, на которой договорились, и его возможности
+    Welcome {
+        version: u32,
+        capabilities: Vec<String>,
+        // кто отвечает и пересылает ли он кадры дальше — нужно для адресной доставки
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        username: Option<String>,
+        #[serde(default, skip_serializing_if = "is_false")]
+        hub: bool,
+    },
+    Challenge {
+        nonce: String,
+    },
+    Auth {
+        response: String,
+        // ключ identity.key и подпись над именем из hello и nonce: к ним сервер
+        // привязывает имя, по которому доставляет личные сообщения
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        public_key: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<String>,
+    },
+    AuthOk,
+    Noise {
+        payload: String,
+    },
+    Encrypted {
+        payload: String,
+    },
+    AuthFail {
+        reason: String,
+    },
+    Chat(Message),
+    // личное сообщение: идёт только к адресату message.to, хаб его не рассылает
+    Direct(Message),
+    // новая версия сообщения от его автора: тот же id, другой текст и новая подпись
+    Edit(Message),
+    // автор удаляет своё сообщение id в момент at (мс); подписан ключом, которым подписано
+    // само сообщение, подпись покрывает id и at
+    Delete {
+        id: String,
+        at: i64,
+        public_key: String,
+        signature: String,
+    },
+    // сообщение id дошло до username
+    Ack {
+        id: String,
+        #[serde(default)]
+        username: String,
+    },
+    // username увидел сообщение id на экране
+    Read {
+        id: String,
+        username: String,
+    },
+    // владелец public_key поставил (on) или снял реакцию emoji на сообщение id;
+    // at — время в мс: из двух версий одной реакции побеждает более поздняя.
+    // подпись покрывает id, emoji, on и at; username только для показа старыми узлами
+    Reaction {
+        id: String,
+        username: String,
+        emoji: String,
+        on: bool,
+        at: i64,
+        #[serde(default)]
+        public_key: String,
+        #[serde(default)]
+        signature: String,
+    },
+    // автор -> время его последнего известного нам сообщения, мс;
+    // updates — то же для правок и удалений (старые узлы его не шлют);
+    // after — продолжение: next из последней пачки предыдущего ответа
+    SyncRequest {
+        watermarks: BTreeMap<String, i64>,
+        #[serde(default)]
+        updates: BTreeMap<String, i64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        after: Option<SyncCursor>,
+    },
+    // next есть у последней пачки, если в ответ влезло не всё
+    SyncBatch {
+        messages: Vec<Message>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        next: Option<SyncCursor>,
+    },
+    // предложение файла; сами данные идут бинарными кадрами после file_accept
+    FileOffer {
+        id: String,
+        name: String,
+        size: u64,
+        sha256: String,
+        sender: String,
+    },
+    // получатель готов принять файл id начиная с offset байт
+    FileAccept {
+        id: String,
+        offset: u64,
+    },
+    Ping,
+    Pong,
+    // кто подключился, отключился, отошёл или вернулся; join и leave объявляет сервер
+    Presence {
+        username: String,
+        status: PresenceStatus,
+    },
+    // username набирает текст в комнате room или личное сообщение для to; живёт несколько секунд
+    Typing {
+        username: String,
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        room: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        to: Option<String>,
+    },
+    Error {
+        message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<ErrorCode>,
+    },
+    // сюда попадает любой тип, которого мы не знаем
+    #[serde(other, skip_serializing)]
+    Unknown,
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    Unknown(String),
+    Malformed(serde_json::Error),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Unknown(kind) => write!(f, "Неизвестный тип кадра: {}", kind),
+            FrameError::Malformed(e) => write!(f, "Некорректный кадр: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+#[derive(Deserialize)]
+struct FrameTag {
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+// наибольшая версия, которую понимают обе стороны
+pub fn negotiate_version(remote_version: u32, remote_min: Option<u32>) -> Result<u32, String> {
+    let version = remote_version.min(PROTOCOL_VERSION);
+    let required = remote_min
+        .unwrap_or(remote_version)
+        .max(MIN_PROTOCOL_VERSION);
+    if version < required {
+        return Err(format!(
+            "Несовместимая версия протокола: мы поддерживаем {}-{}, собеседник {}-{}",
+            MIN_PROTOCOL_VERSION,
+            PROTOCOL_VERSION,
+            remote_min.unwrap_or(remote_version),
+            remote_version
+        ));
+    }
+    Ok(version)
+}
+
+pub fn local_capabilities(noise: bool) -> Vec<String> {
+    let mut capabilities = vec![
+        CAP_SYNC.to_string(),
+        CAP_RECEIPTS.to_string(),
+        CAP_FILES.to_string(),
+        CAP_ROOMS.to_string(),
+        CAP_DIRECT.to_string(),
+        CAP_PRESENCE.to_string(),
+        CAP_TYPING.to_string(),
+        CAP_EDIT.to_string(),
+        CAP_REACTIONS.to_string(),
+    ];
+    if noise {
+        capabilities.push(CAP_NOISE.to_string());
+    }
+    capabilities
+}
+
+pub fn common_capabilities(local: &[String], remote: &[String]) -> Vec<String> {
+    local
+        .iter()
+        .filter(|capability| remote.contains(capability))
+        .cloned()
+        .collect()
+}
+
+impl Frame {
+    pub fn presence(username: &str, status: PresenceStatus) -> Frame {
+        Frame::Presence {
+            username: username.to_string(),
+            status,
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Frame {
+        Frame::Error {
+            message: message.into(),
+            code: None,
+        }
+    }
+
+    // возможность, без которой собеседник этот кадр не поймёт
+    pub fn required_capability(&self) -> Option<&'static str> {
+        match self {
+            Frame::Ack { .. } | Frame::Read { .. } => Some(CAP_RECEIPTS),
+            Frame::SyncRequest { .. } | Frame::SyncBatch { .. } => Some(CAP_SYNC),
+            Frame::FileOffer { .. } | Frame::FileAccept { .. } => Some(CAP_FILES),
+            Frame::Direct(_) => Some(CAP_DIRECT),
+            Frame::Presence { .. } => Some(CAP_PRESENCE),
+            Frame::Typing { .. } => Some(CAP_TYPING),
+            Frame::Edit(_) | Frame::Delete { .. } => Some(CAP_EDIT),
+            Frame::Reaction { .. } => Some(CAP_REACTIONS),
+            // без комнат собеседник смешал бы их в одну ленту
+            Frame::Chat(message) if message.room != DEFAULT_ROOM => Some(CAP_ROOMS),
+            _ => None,
+        }
+    }
+
+    pub fn is_supported_by(&self, capabilities: &[String]) -> bool {
+        self.required_capability()
+            .is_none_or(|required| capabilities.iter().any(|c| c == required))
+    }
+
+    // что хаб пересылает остальным участникам
+    pub fn is_relayable(&self) -> bool {
+        matches!(
+            self,
+            Frame::Chat(_)
+                | Frame::Edit(_)
+                | Frame::Delete { .. }
+                | Frame::Ack { .. }
+                | Frame::Read { .. }
+                | Frame::Reaction { .. }
+        )
+    }
+
+    // ключ, по которому mesh отсекает повторы при пересылке
+    pub fn dedup_key(&self) -> Option<String> {
+        match self {
+            // с подписью: чужой кадр с тем же id не вытеснит настоящий
+            Frame::Chat(message) => Some(format!(
+                "{}:{}",
+                message.id,
+                message.signature.as_deref().unwrap_or_default()
+            )),
+            Frame::Edit(message) => Some(format!(
+                "edit:{}:{}",
+                message.id,
+                message.signature.as_deref().unwrap_or_default()
+            )),
+            Frame::Delete { id, signature, .. } => Some(format!("delete:{}:{}", id, signature)),
+            Frame::Ack { id, username } => Some(format!("ack:{}:{}", id, username)),
+            Frame::Reaction { id, signature, .. } => Some(format!("reaction:{}:{}", id, signature)),
+            Frame::Read { id, username } => Some(format!("read:{}:{}", id, username)),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    pub fn decode(text: &str) -> Result<Frame, FrameError> {
+        match serde_json::from_str::<Frame>(text) {
+            Ok(Frame::Unknown) => {
+                let tag: FrameTag = serde_json::from_str(text).map_err(FrameError::Malformed)?;
+                Err(FrameError::Unknown(tag.kind))
+            }
+            Ok(frame) => Ok(frame),
+            Err(e) => Err(FrameError::Malformed(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    #[test]
+    fn negotiates_supported_versions() {
+        assert_eq!(
+            negotiate_version(PROTOCOL_VERSION, None),
+            Ok(PROTOCOL_VERSION)
+        );
+        assert_eq!(
+            negotiate_version(MIN_PROTOCOL_VERSION, None),
+            Ok(MIN_PROTOCOL_VERSION)
+        );
+        assert_eq!(
+            negotiate_version(PROTOCOL_VERSION, Some(MIN_PROTOCOL_VERSION)),
+            Ok(PROTOCOL_VERSION)
+        );
+    }
+
+    #[test]
+    fn newer_peer_falls_back_to_our_version() {
+        assert_eq!(
+            negotiate_version(PROTOCOL_VERSION + 1, Some(PROTOCOL_VERSION)),
+            Ok(PROTOCOL_VERSION)
+        );
+        assert_eq!(
+            negotiate_version(PROTOCOL_VERSION + 5, Some(MIN_PROTOCOL_VERSION)),
+            Ok(PROTOCOL_VERSION)
+        );
+    }
+
+    #[test]
+    fn rejects_versions_out_of_range() {
+        assert!(negotiate_version(MIN_PROTOCOL_VERSION - 1, None).is_err());
+        assert!(negotiate_version(PROTOCOL_VERSION + 1, None).is_err());
+        assert!(negotiate_version(PROTOCOL_VERSION + 2, Some(PROTOCOL_VERSION + 1)).is_err());
+    }
+
+    #[test]
+    fn common_capabilities_keep_local_order() {
+        let local = strings(&[CAP_SYNC, CAP_RECEIPTS, CAP_FILES, CAP_NOISE]);
+        let remote = strings(&[CAP_NOISE, "future", CAP_SYNC]);
+        assert_eq!(
+            common_capabilities(&local, &remote),
+            strings(&[CAP_SYNC, CAP_NOISE])
+        );
+        assert!(common_capabilities(&local, &[]).is_empty());
+    }
+
+    #[test]
+    fn local_capabilities_include_noise_only_when_enabled() {
+        assert!(!local_capabilities(false).contains(&CAP_NOISE.to_string()));
+        assert!(local_capabilities(true).contains(&CAP_NOISE.to_string()));
+    }
+}
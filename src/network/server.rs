@@ -1,45 +1,66 @@
+use std::net::SocketAddr;
+
 use crate::config::SharedConfig;
-use crate::{models::message::Message, network::message};
-use futures_util::stream::{SplitSink, SplitStream};
+use crate::identity;
+use crate::network::NetEvent;
+use crate::network::hub::ConnectionId;
+use crate::network::mesh::{Mesh, Origin};
+use crate::network::message::{SendError, WsSink, WsStream};
+use crate::network::noise::NoiseSession;
+use crate::network::protocol::{self, CAP_NOISE, ErrorCode, Frame, PresenceStatus};
+use crate::network::transfer::{self, Route};
+use crate::network::{auth, message, noise, sync, tls};
 use futures_util::{SinkExt, StreamExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::{
     net::TcpListener,
     sync::{mpsc, oneshot},
 };
-use tokio_websockets::{MaybeTlsStream, Message as WsMessage, ServerBuilder, WebSocketStream};
+use tokio_websockets::ServerBuilder;
 
 pub struct WebSocketServer {
+    mesh: Mesh,
     config: SharedConfig,
-    user_tx: mpsc::Sender<Message>,
+    user_tx: mpsc::Sender<NetEvent>,
     server_ready_tx: Option<oneshot::Sender<()>>,
 }
 
 impl WebSocketServer {
-    pub fn new(
-        config: SharedConfig,
-        user_tx: mpsc::Sender<Message>,
-        server_ready_tx: oneshot::Sender<()>,
-    ) -> Self {
+    pub fn new(mesh: Mesh, server_ready_tx: oneshot::Sender<()>) -> Self {
         Self {
-            config,
-            user_tx,
+            config: mesh.config().clone(),
+            user_tx: mesh.user_tx().clone(),
+            mesh,
             server_ready_tx: Some(server_ready_tx),
         }
     }
 
     pub async fn run(&mut self) {
-        let (addr, valid_token) = (
-            self.config.server_addr().to_string(),
-            self.config.token().to_string(),
-        );
+        let addr = self.config.server_addr().to_string();
         let addr = addr.as_str();
-        let valid_token = valid_token.to_string();
+
+        let acceptor = if self.config.tls_enabled() {
+            match tls::server_acceptor(&self.config) {
+                Ok((acceptor, fingerprint)) => {
+                    let notice = format!("TLS включён, отпечаток сертификата: {}", fingerprint);
+                    let _ = self.user_tx.send(NetEvent::Notice(notice)).await;
+                    Some(acceptor)
+                }
+                Err(e) => {
+                    let notice = format!("Ошибка настройки TLS: {}", e);
+                    let _ = self.user_tx.send(NetEvent::Notice(notice)).await;
+                    return;
+                }
+            }
+        } else {
+            None
+        };
 
         let listener = match TcpListener::bind(addr).await {
             Ok(l) => l,
             Err(e) => {
-                eprintln!("Ошибка привязки адреса {}: {}", addr, e);
+                let notice = format!("Ошибка привязки адреса {}: {}", addr, e);
+                let _ = self.user_tx.send(NetEvent::Notice(notice)).await;
                 return;
             }
         };
@@ -49,52 +70,391 @@ impl WebSocketServer {
 
         while let Ok((stream, peer_addr)) = listener.accept().await {
             // println!("Новое подключение от {}", peer_addr);
-            let user_tx = self.user_tx.clone();
-            let valid_token = valid_token.clone();
+            let mesh = self.mesh.clone();
+            let acceptor = acceptor.clone();
 
             tokio::spawn(async move {
-                let ws_stream: WebSocketStream<MaybeTlsStream<TcpStream>> =
-                    match ServerBuilder::new()
-                        .accept(MaybeTlsStream::Plain(stream))
-                        .await
-                    {
-                        Ok((_request, ws_stream)) => ws_stream,
+                match acceptor {
+                    Some(acceptor) => match acceptor.accept(stream).await {
+                        Ok(stream) => handle_client(stream, peer_addr, mesh).await,
                         Err(e) => {
-                            eprintln!("Ошибка установления WebSocket: {}", e);
-                            return;
+                            let notice = format!("Ошибка TLS-рукопожатия с {}: {}", peer_addr, e);
+                            let _ = mesh.user_tx().send(NetEvent::Notice(notice)).await;
                         }
+                    },
+                    None => handle_client(stream, peer_addr, mesh).await,
+                }
+            });
+        }
+    }
+}
+
+async fn handle_client<S>(stream: S, peer_addr: SocketAddr, mesh: Mesh)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (config, user_tx) = (mesh.config(), mesh.user_tx());
+    let ws_stream = match ServerBuilder::new().accept(stream).await {
+        Ok((_request, ws_stream)) => ws_stream,
+        Err(e) => {
+            let notice = format!("Ошибка установления WebSocket с {}: {}", peer_addr, e);
+            let _ = user_tx.send(NetEvent::Notice(notice)).await;
+            return;
+        }
+    };
+    let (mut sink, mut stream) = ws_stream.split();
+
+    let (username, version, remote_capabilities) = match message::read_frame(&mut stream).await {
+        Some(Frame::Hello {
+            version,
+            username,
+            min_version,
+            capabilities,
+        }) => match protocol::negotiate_version(version, min_version) {
+            Ok(version) => (username, version, capabilities),
+            Err(reason) => {
+                let notice = format!("Клиент {}: {}", peer_addr, reason);
+                let _ = user_tx.send(NetEvent::Notice(notice)).await;
+                let error = Frame::Error {
+                    message: reason,
+                    code: Some(ErrorCode::IncompatibleVersion),
+                };
+                let _ = message::send_frame(&mut sink, &error).await;
+                return;
+            }
+        },
+        _ => {
+            let notice = format!("Клиент {} не представился", peer_addr);
+            let _ = user_tx.send(NetEvent::Notice(notice)).await;
+            return;
+        }
+    };
+
+    // с Noise на сервере клиент без Noise всё равно не договорится — говорим об этом сразу
+    if config.noise_enabled() && !remote_capabilities.iter().any(|c| c == CAP_NOISE) {
+        let error = Frame::Error {
+            message: "Сервер требует сквозное шифрование: запустите клиент с --noise".to_string(),
+            code: Some(ErrorCode::MissingCapability),
+        };
+        let _ = message::send_frame(&mut sink, &error).await;
+        return;
+    }
+
+    let local_capabilities = protocol::local_capabilities(config.noise_enabled());
+    let capabilities = protocol::common_capabilities(&local_capabilities, &remote_capabilities);
+    // клиенты первой версии welcome не знают и ждут сразу челлендж
+    if version >= 2 {
+        let welcome = Frame::Welcome {
+            version,
+            capabilities: local_capabilities,
+            username: Some(mesh.username()),
+            hub: config.hub_enabled(),
+        };
+        if message::send_frame(&mut sink, &welcome).await.is_err() {
+            return;
+        }
+    }
+
+    // токен по сети не ходит: проверяем HMAC от случайного nonce
+    let nonce = auth::generate_nonce();
+    let challenge = Frame::Challenge {
+        nonce: nonce.clone(),
+    };
+    if message::send_frame(&mut sink, &challenge).await.is_err() {
+        return;
+    }
+
+    let (response, public_key, signature) = match message::read_frame(&mut stream).await {
+        Some(Frame::Auth {
+            response,
+            public_key,
+            signature,
+        }) => (response, public_key, signature),
+        _ => {
+            let notice = format!("Некорректное сообщение авторизации от {}", peer_addr);
+            let _ = user_tx.send(NetEvent::Notice(notice)).await;
+            return;
+        }
+    };
+
+    if !auth::verify_challenge(config.token(), &nonce, &response) {
+        let notice = format!("Неверный токен от {} ({})", peer_addr, username);
+        let _ = user_tx.send(NetEvent::Notice(notice)).await;
+        let fail = Frame::AuthFail {
+            reason: "Неверный токен".to_string(),
+        };
+        let _ = message::send_frame(&mut sink, &fail).await;
+        return;
+    }
+    // имя привязываем к ключу identity.key; старые клиенты входят без него, но личные
+    // сообщения им напрямую не доставляются
+    let public_key = match (public_key, signature) {
+        (Some(public_key), Some(signature))
+            if identity::verify_auth(&username, &nonce, &public_key, &signature) =>
+        {
+            Some(public_key)
+        }
+        (None, None) => None,
+        _ => {
+            let fail = Frame::AuthFail {
+                reason: "Неверная подпись входа".to_string(),
+            };
+            let _ = message::send_frame(&mut sink, &fail).await;
+            return;
+        }
+    };
+    if !mesh.can_register_inbound(&username, public_key.as_deref()) {
+        let fail = Frame::AuthFail {
+            reason: format!("Имя {} уже занято другим участником", username),
+        };
+        let _ = message::send_frame(&mut sink, &fail).await;
+        return;
+    }
+    if message::send_frame(&mut sink, &Frame::AuthOk)
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    let session = match mesh.noise_keys() {
+        Some(keys) => {
+            match noise::respond(&mut sink, &mut stream, keys, config.noise_peer_keys()).await {
+                Ok(session) => {
+                    let notice = format!(
+                        "Защищённое соединение от {} ({}), ключ: {}",
+                        username,
+                        peer_addr,
+                        session.remote_key()
+                    );
+                    let _ = user_tx.send(NetEvent::Notice(notice)).await;
+                    Some(session)
+                }
+                Err(e) => {
+                    let notice = format!("Ошибка Noise-рукопожатия с {}: {}", peer_addr, e);
+                    let _ = user_tx.send(NetEvent::Notice(notice)).await;
+                    let error = Frame::error("Noise-рукопожатие не удалось");
+                    let _ = message::send_frame(&mut sink, &error).await;
+                    return;
+                }
+            }
+        }
+        None => None,
+    };
+
+    serve_connection(
+        stream,
+        sink,
+        session,
+        capabilities,
+        username,
+        public_key,
+        mesh,
+    )
+    .await;
+}
+
+// запись идёт через отдельную задачу, чтобы хаб мог слать кадры в соединение в любой момент
+async fn serve_connection<S>(
+    mut stream: WsStream<S>,
+    mut sink: WsSink<S>,
+    session: Option<NoiseSession>,
+    capabilities: Vec<String>,
+    username: String,
+    public_key: Option<String>,
+    mesh: Mesh,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (conn_tx, mut conn_rx) = mpsc::channel::<Frame>(100);
+    // пока шло рукопожатие, имя мог занять кто-то другой
+    if let Err(reason) = mesh.register_inbound(&username, public_key, conn_tx.clone()) {
+        let _ = message::try_send_secure(&mut sink, &Frame::error(reason), session.as_ref()).await;
+        return;
+    }
+    let (chunk_tx, mut chunk_rx) = mpsc::channel::<Vec<u8>>(transfer::CHUNK_QUEUE);
+    let writer_session = session.clone();
+    let writer_events = mesh.user_tx().clone();
+    let writer_name = username.clone();
+    let writer = tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                frame = conn_rx.recv() => {
+                    let Some(frame) = frame else {
+                        break;
                     };
-                let (mut sink, mut stream): (SplitSink<_, WsMessage>, SplitStream<_>) =
-                    ws_stream.split();
-
-                let auth_message = match stream.next().await {
-                    Some(Ok(msg)) => msg,
-                    _ => {
-                        eprintln!("Клиент {} не отправил токен", peer_addr);
-                        return;
+                    // то, чего клиент не поддерживает, он показал бы как неизвестный кадр
+                    if !frame.is_supported_by(&capabilities) {
+                        continue;
                     }
-                };
+                    match message::try_send_secure(&mut sink, &frame, writer_session.as_ref()).await {
+                        Ok(()) => {}
+                        // не зашифровался — выбрасываем только этот кадр
+                        Err(e @ SendError::Encrypt(_)) => {
+                            let notice = format!("Кадр для {} не отправлен: {}", writer_name, e);
+                            let _ = writer_events.send(NetEvent::Notice(notice)).await;
+                        }
+                        Err(SendError::Socket(_)) => break,
+                    }
+                }
+                // сокет уже не пишется — выходим, отправка файла увидит обрыв
+                Some(chunk) = chunk_rx.recv() => {
+                    if message::try_send_chunk(&mut sink, &chunk, writer_session.as_ref())
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            }
+        }
+        let _ = sink.close().await;
+    });
+    let conn_id = mesh.hub().map(|hub| hub.register(conn_tx.clone()));
+    let route = Route::Inbound(username.clone());
+    announce(
+        &mesh,
+        Frame::presence(&username, PresenceStatus::Join),
+        conn_id,
+    )
+    .await;
+    for presence in mesh.roster(&username) {
+        let _ = conn_tx.send(presence).await;
+    }
+    let _ = conn_tx.send(sync::request(mesh.history())).await;
+    for request in mesh.transfers().resume(&route) {
+        let _ = conn_tx.send(request).await;
+    }
+
+    while let Some(Ok(msg)) = stream.next().await {
+        if msg.is_binary() {
+            match message::open_chunk(msg.as_payload(), session.as_ref()) {
+                Ok(chunk) => mesh.transfers().receive_chunk(&chunk).await,
+                Err(e) => {
+                    let notice =
+                        format!("Не удалось расшифровать кусок файла от {}: {}", username, e);
+                    let _ = mesh.user_tx().send(NetEvent::Notice(notice)).await;
+                }
+            }
+            continue;
+        }
+        let Some(text) = msg.as_text() else {
+            continue;
+        };
 
-                let message: Message = match auth_message
-                    .as_text()
-                    .and_then(|text| serde_json::from_str(text).ok())
-                {
-                    Some(msg) => msg,
+        let event = match message::parse_event(text, session.as_ref()) {
+            NetEvent::Frame(Frame::Ping) => {
+                let _ = conn_tx.send(Frame::Pong).await;
+                continue;
+            }
+            NetEvent::Frame(Frame::Pong) => continue,
+            NetEvent::Frame(frame @ Frame::SyncRequest { .. }) => {
+                for batch in sync::respond(mesh.history(), &frame) {
+                    let _ = conn_tx.send(batch).await;
+                }
+                continue;
+            }
+            NetEvent::Frame(frame @ Frame::SyncBatch { .. }) => {
+                if let Some(request) = sync::follow_up(mesh.history(), &frame) {
+                    let _ = conn_tx.send(request).await;
+                }
+                for event in sync::backfill(frame) {
+                    let _ = mesh.user_tx().send(event).await;
+                }
+                continue;
+            }
+            NetEvent::Frame(frame @ Frame::FileOffer { .. }) => {
+                mesh.transfers().offered(frame, route.clone()).await;
+                continue;
+            }
+            NetEvent::Frame(Frame::FileAccept { id, offset }) => {
+                mesh.transfers()
+                    .start_upload(&id, offset, &username, chunk_tx.clone());
+                continue;
+            }
+            // клиент сообщает только о себе и только отошёл/вернулся — join и leave объявляем мы
+            NetEvent::Frame(Frame::Presence {
+                username: who,
+                status: status @ (PresenceStatus::Away | PresenceStatus::Back),
+            }) if who == username => {
+                mesh.set_away(&username, status == PresenceStatus::Away);
+                announce(&mesh, Frame::presence(&username, status), conn_id).await;
+                continue;
+            }
+            NetEvent::Frame(Frame::Presence { .. }) => continue,
+            // набор текста хаб передаёт дальше: для комнаты — всем, для личного — адресату
+            NetEvent::Frame(Frame::Typing {
+                username: who,
+                room,
+                to,
+            }) if who == username => {
+                let typing = Frame::Typing {
+                    username: who,
+                    room,
+                    to: to.clone(),
+                };
+                match to {
+                    Some(to) if to != mesh.username() => {
+                        if mesh.hub().is_some() {
+                            mesh.send_typing(&to, typing);
+                        }
+                    }
+                    Some(_) => {
+                        let _ = mesh.user_tx().send(NetEvent::Frame(typing)).await;
+                    }
                     None => {
-                        eprintln!("Некорректное сообщение авторизации от {}", peer_addr);
-                        return;
+                        if let Some(hub) = mesh.hub() {
+                            hub.broadcast(&typing, conn_id);
+                        }
+                        let _ = mesh.user_tx().send(NetEvent::Frame(typing)).await;
                     }
-                };
-
-                if message.token != valid_token {
-                    eprintln!("Неверный токен от {}: {}", peer_addr, message.token);
-                    let _ = sink.send(WsMessage::text("Неверный токен")).await;
-                    return;
                 }
-                // println!("Клиент {} авторизован", peer_addr);
+                continue;
+            }
+            NetEvent::Frame(Frame::Typing { .. }) => continue,
+            // личное сообщение не нам: в ленту не попадает и всем не рассылается,
+            // хаб передаёт его только адресату
+            NetEvent::Frame(Frame::Direct(message)) if message.to != Some(mesh.username()) => {
+                let to = message.to.clone().unwrap_or_default();
+                if mesh.hub().is_some() && !mesh.forward_direct(message).await {
+                    let error = format!("{} не в сети, личное сообщение не доставлено", to);
+                    let _ = conn_tx.send(Frame::error(error)).await;
+                }
+                continue;
+            }
+            event => event,
+        };
 
-                tokio::spawn(async move { message::receive_messages(stream, sink, user_tx).await });
-            });
+        if !mesh.accept(&event, Origin::Inbound(conn_id)) {
+            continue;
+        }
+
+        // уи закрылся — читать дальше некому
+        if mesh.user_tx().send(event).await.is_err() {
+            break;
         }
     }
+
+    if let (Some(hub), Some(id)) = (mesh.hub(), conn_id) {
+        hub.unregister(id);
+    }
+    mesh.unregister_inbound(&username, &conn_tx);
+    mesh.set_away(&username, false);
+    announce(
+        &mesh,
+        Frame::presence(&username, PresenceStatus::Leave),
+        conn_id,
+    )
+    .await;
+    drop(conn_tx);
+    drop(chunk_tx);
+    let _ = writer.await;
+}
+
+// статус клиента видит наш пользователь, а у хаба — и все остальные клиенты
+async fn announce(mesh: &Mesh, presence: Frame, from: Option<ConnectionId>) {
+    if let Some(hub) = mesh.hub() {
+        hub.broadcast(&presence, from);
+    }
+    let _ = mesh.user_tx().send(NetEvent::Frame(presence)).await;
 }
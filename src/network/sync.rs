@@ -0,0 +1,104 @@
+use crate::network::NetEvent;
+use crate::network::protocol::Frame;
+use crate::storage::history::{SharedHistory, SyncCursor};
+
+// больше за один ответ не досылаем, остальное — по следующему запросу
+const SYNC_LIMIT: usize = 500;
+// кадр с пачкой должен влезать в одно Noise-сообщение (64 КиБ) — считаем байты, а не штуки,
+// с запасом на обёртку кадра
+const BATCH_BYTES: usize = 60 * 1024;
+
+// после подключения рассказываем собеседнику, до какого момента мы знаем каждого автора
+pub fn request(history: &SharedHistory) -> Frame {
+    request_after(history, None)
+}
+
+// последняя пачка ответа упёрлась в лимит — просим продолжение с того места, где она кончилась
+pub fn follow_up(history: &SharedHistory, frame: &Frame) -> Option<Frame> {
+    let Frame::SyncBatch {
+        next: Some(next), ..
+    } = frame
+    else {
+        return None;
+    };
+    Some(request_after(history, Some(*next)))
+}
+
+fn request_after(history: &SharedHistory, after: Option<SyncCursor>) -> Frame {
+    let history = history.lock().unwrap();
+    Frame::SyncRequest {
+        watermarks: history.watermarks().unwrap_or_default(),
+        updates: history.update_marks().unwrap_or_default(),
+        after,
+    }
+}
+
+// ответ на SyncRequest: недостающие сообщения пачками, от старых к новым
+pub fn respond(history: &SharedHistory, frame: &Frame) -> Vec<Frame> {
+    let Frame::SyncRequest {
+        watermarks,
+        updates,
+        after,
+    } = frame
+    else {
+        return Vec::new();
+    };
+    let (missing, next) = history
+        .lock()
+        .unwrap()
+        .since(watermarks, updates, *after, SYNC_LIMIT)
+        .unwrap_or_default();
+
+    // сообщение крупнее лимита уходит отдельной пачкой
+    let mut batches = Vec::new();
+    let mut batch = Vec::new();
+    let mut size = 0;
+    for message in missing {
+        let encoded = serde_json::to_vec(&message).map_or(0, |bytes| bytes.len()) + 1;
+        if !batch.is_empty() && size + encoded > BATCH_BYTES {
+            batches.push(Frame::SyncBatch {
+                messages: std::mem::take(&mut batch),
+                next: None,
+            });
+            size = 0;
+        }
+        size += encoded;
+        batch.push(message);
+    }
+    if !batch.is_empty() {
+        batches.push(Frame::SyncBatch {
+            messages: batch,
+            next,
+        });
+    }
+    batches
+}
+
+// присланные сообщения идут в уи почти как обычные (только без квитанций): там их проверят,
+// отсеют дубли и вставят по времени;
+// исправленное дублируем правкой — у собеседника может быть старый текст, а удалённое
+// превращаем обратно в подписанное удаление
+pub fn backfill(frame: Frame) -> Vec<NetEvent> {
+    let Frame::SyncBatch { messages, .. } = frame else {
+        return Vec::new();
+    };
+    let mut events = Vec::new();
+    for message in messages {
+        if message.is_deleted() {
+            if let (Some(public_key), Some(signature)) = (&message.public_key, &message.signature) {
+                events.push(NetEvent::Frame(Frame::Delete {
+                    id: message.id.clone(),
+                    at: message.updated_at(),
+                    public_key: public_key.clone(),
+                    signature: signature.clone(),
+                }));
+            }
+        } else if message.is_edited() {
+            events.push(NetEvent::Backfill(Box::new(message.clone())));
+            events.push(NetEvent::Frame(Frame::Edit(message)));
+        } else {
+            events.push(NetEvent::Backfill(Box::new(message)));
+        }
+    }
+    events
+}
@@ -0,0 +1,168 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use sha2::{Digest, Sha256};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{self, CryptoProvider};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{
+    self, ClientConfig, DigitallySignedStruct, ServerConfig, SignatureScheme,
+};
+use tokio_websockets::Connector;
+
+use crate::config::SharedConfig;
+
+type TlsResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+// отпечаток сертификата: sha256 от DER в hex
+pub fn fingerprint(cert: &CertificateDer<'_>) -> String {
+    hex::encode(Sha256::digest(cert.as_ref()))
+}
+
+// пользователь может вставить отпечаток с двоеточиями и в любом регистре
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| c.is_ascii_hexdigit())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+pub fn server_acceptor(config: &SharedConfig) -> TlsResult<(TlsAcceptor, String)> {
+    let (cert_path, key_path) = (config.tls_cert(), config.tls_key());
+
+    if !cert_path.exists() || !key_path.exists() {
+        if !config.tls_self_signed() {
+            return Err(format!(
+                "не найдены сертификат {} или ключ {} (используйте --tls-self-signed)",
+                cert_path.display(),
+                key_path.display()
+            )
+            .into());
+        }
+        generate_self_signed(cert_path, key_path)?;
+    }
+
+    let cert = CertificateDer::from_pem_file(cert_path)?;
+    let key = PrivateKeyDer::from_pem_file(key_path)?;
+    let fingerprint = fingerprint(&cert);
+
+    let server_config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(vec![cert], key)?;
+
+    Ok((TlsAcceptor::from(Arc::new(server_config)), fingerprint))
+}
+
+// самоподписанный сертификат сохраняем на диск, чтобы отпечаток не менялся между запусками;
+// закрытый ключ читает только владелец
+fn generate_self_signed(cert_path: &Path, key_path: &Path) -> TlsResult<()> {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+    for path in [cert_path, key_path] {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+    }
+    std::fs::write(cert_path, certified.cert.pem())?;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut key_file = options.open(key_path)?;
+    std::io::Write::write_all(&mut key_file, certified.key_pair.serialize_pem().as_bytes())?;
+    Ok(())
+}
+
+pub fn pinned_connector(fingerprints: &[String]) -> TlsResult<Connector> {
+    let verifier = PinnedCertVerifier {
+        fingerprints: fingerprints
+            .iter()
+            .map(|fingerprint| normalize_fingerprint(fingerprint))
+            .collect(),
+        provider: provider(),
+    };
+
+    let client_config = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+
+    Ok(Connector::Rustls(TlsConnector::from(Arc::new(
+        client_config,
+    ))))
+}
+
+// без CA: доверяем только сертификатам с заранее известными отпечатками
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    fingerprints: Vec<String>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.fingerprints.contains(&fingerprint(end_entity)) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "отпечаток сертификата собеседника не совпадает".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
@@ -0,0 +1,610 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+
+use crate::network::NetEvent;
+use crate::network::protocol::Frame;
+
+// кусок файла в одном бинарном кадре; с заголовком и тегом Noise влезает в сообщение Noise
+pub const CHUNK_SIZE: usize = 16 * 1024;
+// сколько кусков ждут записи в сокет; больше не читаем — чат не стоит за файлом в очереди
+pub const CHUNK_QUEUE: usize = 8;
+
+type TransferResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+// соединение, по которому пришло предложение: туда уходит согласие и повтор после обрыва
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Route {
+    // наше исходящее соединение к адресу
+    Peer(String),
+    // входящее соединение от пользователя
+    Inbound(String),
+}
+
+impl Route {
+    pub fn label(&self) -> &str {
+        match self {
+            Route::Peer(addr) => addr,
+            Route::Inbound(username) => username,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferState {
+    Offered,
+    Running,
+    // у загрузки — путь, куда сохранили файл
+    Done(Option<PathBuf>),
+    // соединение оборвалось; получатель попросит продолжить после переподключения
+    Interrupted,
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct TransferProgress {
+    pub id: String,
+    pub name: String,
+    // от кого файл или кому отправляем
+    pub peer: String,
+    pub direction: Direction,
+    pub done: u64,
+    pub total: u64,
+    pub state: TransferState,
+}
+
+impl TransferProgress {
+    pub fn percent(&self) -> u64 {
+        percent(self.done, self.total)
+    }
+}
+
+struct OutgoingFile {
+    path: PathBuf,
+    name: String,
+    size: u64,
+    // идущие отправки: канал соединения и задача, которая в него пишет
+    uploads: Vec<(mpsc::Sender<Vec<u8>>, AbortHandle)>,
+}
+
+struct IncomingFile {
+    name: String,
+    size: u64,
+    sha256: String,
+    sender: String,
+    route: Route,
+    // пользователь принял файл; download появится, когда откроется .part
+    accepted: bool,
+    download: Option<Download>,
+}
+
+struct Download {
+    // пишем вне общей блокировки, чтобы диск не задерживал остальные передачи
+    file: Arc<Mutex<File>>,
+    part: PathBuf,
+    hasher: Sha256,
+    received: u64,
+}
+
+// что делать с пришедшим куском
+enum Chunk {
+    // чужой, повтор или загрузка не начата
+    Skip,
+    Failed(TransferProgress),
+    // before — процент до куска, чтобы не сообщать о каждом
+    Write {
+        file: Arc<Mutex<File>>,
+        received: u64,
+        before: u64,
+        size: u64,
+    },
+}
+
+#[derive(Default)]
+struct TransfersInner {
+    outgoing: HashMap<String, OutgoingFile>,
+    incoming: HashMap<String, IncomingFile>,
+}
+
+// передачи файлов поверх соединений с пирами
+#[derive(Clone)]
+pub struct Transfers {
+    download_dir: PathBuf,
+    user_tx: mpsc::Sender<NetEvent>,
+    inner: Arc<Mutex<TransfersInner>>,
+}
+
+impl Transfers {
+    pub fn new(download_dir: PathBuf, user_tx: mpsc::Sender<NetEvent>) -> Self {
+        Self {
+            download_dir,
+            user_tx,
+            inner: Arc::new(Mutex::new(TransfersInner::default())),
+        }
+    }
+
+    async fn report(&self, progress: TransferProgress) {
+        let _ = self.user_tx.send(NetEvent::Transfer(progress)).await;
+    }
+
+    // считаем хеш и запоминаем файл; само предложение рассылает mesh
+    pub async fn offer(&self, path: PathBuf, sender: &str) -> TransferResult<Frame> {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or("в пути нет имени файла")?;
+        let hashed = path.clone();
+        let (size, sha256) = tokio::task::spawn_blocking(move || hash_file(&hashed)).await??;
+
+        let id = uuid::Uuid::new_v4().simple().to_string()[..8].to_string();
+        self.inner.lock().unwrap().outgoing.insert(
+            id.clone(),
+            OutgoingFile {
+                path,
+                name: name.clone(),
+                size,
+                uploads: Vec::new(),
+            },
+        );
+        self.report(TransferProgress {
+            id: id.clone(),
+            name: name.clone(),
+            peer: String::new(),
+            direction: Direction::Upload,
+            done: 0,
+            total: size,
+            state: TransferState::Offered,
+        })
+        .await;
+
+        Ok(Frame::FileOffer {
+            id,
+            name,
+            size,
+            sha256,
+            sender: sender.to_string(),
+        })
+    }
+
+    // предложение от собеседника; тот же id по второму соединению не повторяем
+    pub async fn offered(&self, frame: Frame, route: Route) {
+        let Frame::FileOffer {
+            id,
+            name,
+            size,
+            sha256,
+            sender,
+        } = frame
+        else {
+            return;
+        };
+        // хеш становится именем .part-файла, поэтому пускаем только настоящий sha256
+        if sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return;
+        }
+        let name = safe_name(&name);
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.incoming.contains_key(&id) {
+                return;
+            }
+            inner.incoming.insert(
+                id.clone(),
+                IncomingFile {
+                    name: name.clone(),
+                    size,
+                    sha256,
+                    sender: sender.clone(),
+                    route,
+                    accepted: false,
+                    download: None,
+                },
+            );
+        }
+        self.report(TransferProgress {
+            id,
+            name,
+            peer: sender,
+            direction: Direction::Download,
+            done: 0,
+            total: size,
+            state: TransferState::Offered,
+        })
+        .await;
+    }
+
+    // пользователь принял файл; недокачанное с прошлого раза дописываем, а не качаем заново
+    // None — файл уже целиком лежит на диске и запрашивать нечего
+    pub async fn accept(&self, id: &str) -> TransferResult<Option<(Route, Frame)>> {
+        let (part, size) = {
+            let mut inner = self.inner.lock().unwrap();
+            let incoming = inner
+                .incoming
+                .get_mut(id)
+                .ok_or_else(|| format!("нет предложения файла {}", id))?;
+            if incoming.accepted {
+                return Err(format!("файл {} уже загружается", incoming.name).into());
+            }
+            incoming.accepted = true;
+            let part = self.download_dir.join(format!("{}.part", incoming.sha256));
+            (part, incoming.size)
+        };
+
+        // каталог и .part открываем в фоне: недокачанное приходится хешировать заново
+        let download_dir = self.download_dir.clone();
+        let opened = tokio::task::spawn_blocking(move || {
+            fs::create_dir_all(&download_dir)?;
+            open_part(part, size)
+        })
+        .await?;
+
+        let finished = {
+            let mut inner = self.inner.lock().unwrap();
+            let incoming = inner
+                .incoming
+                .get_mut(id)
+                .ok_or_else(|| format!("нет предложения файла {}", id))?;
+            let download = match opened {
+                Ok(download) => download,
+                Err(e) => {
+                    incoming.accepted = false;
+                    return Err(e.into());
+                }
+            };
+            let offset = download.received;
+            incoming.download = Some(download);
+
+            if offset < size {
+                let frame = Frame::FileAccept {
+                    id: id.to_string(),
+                    offset,
+                };
+                return Ok(Some((incoming.route.clone(), frame)));
+            }
+            inner.incoming.remove(id).unwrap()
+        };
+        self.finish(id, finished).await;
+        Ok(None)
+    }
+
+    // после переподключения просим досылать всё, что не докачали через это соединение
+    pub fn resume(&self, route: &Route) -> Vec<Frame> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .incoming
+            .iter()
+            .filter(|(_, incoming)| incoming.route == *route)
+            .filter_map(|(id, incoming)| {
+                incoming
+                    .download
+                    .as_ref()
+                    .map(|download| Frame::FileAccept {
+                        id: id.clone(),
+                        offset: download.received,
+                    })
+            })
+            .collect()
+    }
+
+    // собеседник готов принять наш файл: пишем куски в канал его соединения
+    pub fn start_upload(&self, id: &str, offset: u64, peer: &str, chunks: mpsc::Sender<Vec<u8>>) {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(outgoing) = inner.outgoing.get_mut(id) else {
+            return;
+        };
+        // повторный запрос по тому же соединению заменяет прежнюю отправку
+        outgoing.uploads.retain(|(tx, handle)| {
+            let replaced = tx.same_channel(&chunks);
+            if replaced {
+                handle.abort();
+            }
+            !replaced && !tx.is_closed()
+        });
+
+        let progress = TransferProgress {
+            id: id.to_string(),
+            name: outgoing.name.clone(),
+            peer: peer.to_string(),
+            direction: Direction::Upload,
+            done: offset.min(outgoing.size),
+            total: outgoing.size,
+            state: TransferState::Running,
+        };
+        let task = tokio::spawn(upload(
+            progress,
+            outgoing.path.clone(),
+            chunks.clone(),
+            self.user_tx.clone(),
+        ));
+        outgoing.uploads.push((chunks, task.abort_handle()));
+    }
+
+    pub async fn receive_chunk(&self, bytes: &[u8]) {
+        let Some((id, offset, data)) = decode_chunk(bytes) else {
+            return;
+        };
+        let (file, received, before, size) = match self.reserve_chunk(id, offset, data) {
+            Chunk::Write {
+                file,
+                received,
+                before,
+                size,
+            } => (file, received, before, size),
+            Chunk::Skip => return,
+            Chunk::Failed(progress) => return self.report(progress).await,
+        };
+
+        let data = data.to_vec();
+        let written = tokio::task::spawn_blocking(move || file.lock().unwrap().write_all(&data))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|result| result.map_err(|e| e.to_string()));
+
+        let (running, ended) = {
+            let mut inner = self.inner.lock().unwrap();
+            if written.is_ok() && received < size {
+                let running = inner
+                    .incoming
+                    .get(id)
+                    .filter(|_| percent(received, size) != before)
+                    .map(|incoming| incoming.progress(id, TransferState::Running));
+                (running, None)
+            } else {
+                (None, inner.incoming.remove(id))
+            }
+        };
+        // прогресс сообщаем, только когда он заметно изменился
+        if let Some(progress) = running {
+            self.report(progress).await;
+        }
+        let Some(incoming) = ended else {
+            return;
+        };
+        match written {
+            Ok(()) => self.finish(id, incoming).await,
+            Err(reason) => {
+                self.report(incoming.progress(id, TransferState::Failed(reason)))
+                    .await
+            }
+        }
+    }
+
+    // учитываем кусок до записи на диск: куски одного соединения идут по очереди,
+    // поэтому следующий придёт уже после неё
+    fn reserve_chunk(&self, id: &str, offset: u64, data: &[u8]) -> Chunk {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(incoming) = inner.incoming.get_mut(id) else {
+            return Chunk::Skip;
+        };
+        let size = incoming.size;
+        let Some(download) = incoming.download.as_mut() else {
+            return Chunk::Skip;
+        };
+        // повтор или хвост прерванной отправки — эти данные у нас уже есть
+        if offset != download.received {
+            return Chunk::Skip;
+        }
+        let before = percent(download.received, size);
+        if download.received + data.len() as u64 > size {
+            let incoming = inner.incoming.remove(id).unwrap();
+            let reason = "получено больше данных, чем заявлено".to_string();
+            return Chunk::Failed(incoming.progress(id, TransferState::Failed(reason)));
+        }
+        download.hasher.update(data);
+        download.received += data.len() as u64;
+        Chunk::Write {
+            file: download.file.clone(),
+            received: download.received,
+            before,
+            size,
+        }
+    }
+
+    // сверяем хеш и переносим файл из .part под настоящим именем
+    async fn finish(&self, id: &str, incoming: IncomingFile) {
+        let download_dir = self.download_dir.clone();
+        let id = id.to_string();
+        let progress =
+            tokio::task::spawn_blocking(move || finish(&download_dir, &id, incoming)).await;
+        if let Ok(progress) = progress {
+            self.report(progress).await;
+        }
+    }
+}
+
+fn finish(download_dir: &Path, id: &str, mut incoming: IncomingFile) -> TransferProgress {
+    let Some(download) = incoming.download.take() else {
+        return incoming.progress(id, TransferState::Failed("файл не принимался".into()));
+    };
+    let Download {
+        file, part, hasher, ..
+    } = download;
+    drop(file);
+
+    let state = if hex::encode(hasher.finalize()) != incoming.sha256 {
+        let _ = fs::remove_file(&part);
+        TransferState::Failed("контрольная сумма не совпала, файл удалён".to_string())
+    } else {
+        let path = unique_path(download_dir, &incoming.name);
+        match fs::rename(&part, &path) {
+            Ok(()) => TransferState::Done(Some(path)),
+            Err(e) => TransferState::Failed(format!("не удалось сохранить файл: {}", e)),
+        }
+    };
+    let mut progress = incoming.progress(id, state);
+    progress.done = progress.total;
+    progress
+}
+
+impl IncomingFile {
+    fn progress(&self, id: &str, state: TransferState) -> TransferProgress {
+        TransferProgress {
+            id: id.to_string(),
+            name: self.name.clone(),
+            peer: self.sender.clone(),
+            direction: Direction::Download,
+            done: self
+                .download
+                .as_ref()
+                .map_or(0, |download| download.received),
+            total: self.size,
+            state,
+        }
+    }
+}
+
+async fn upload(
+    mut progress: TransferProgress,
+    path: PathBuf,
+    chunks: mpsc::Sender<Vec<u8>>,
+    user_tx: mpsc::Sender<NetEvent>,
+) {
+    let _ = user_tx.send(NetEvent::Transfer(progress.clone())).await;
+    progress.state = match send_file(&mut progress, &path, &chunks, &user_tx).await {
+        Ok(()) => TransferState::Done(None),
+        Err(_) if chunks.is_closed() => TransferState::Interrupted,
+        Err(e) => TransferState::Failed(e.to_string()),
+    };
+    let _ = user_tx.send(NetEvent::Transfer(progress)).await;
+}
+
+async fn send_file(
+    progress: &mut TransferProgress,
+    path: &Path,
+    chunks: &mpsc::Sender<Vec<u8>>,
+    user_tx: &mpsc::Sender<NetEvent>,
+) -> TransferResult<()> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(progress.done)).await?;
+
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut reported = progress.percent();
+    while progress.done < progress.total {
+        let left = (progress.total - progress.done).min(CHUNK_SIZE as u64) as usize;
+        let read = file.read(&mut buf[..left]).await?;
+        if read == 0 {
+            return Err("файл стал короче, чем был при отправке предложения".into());
+        }
+        chunks
+            .send(encode_chunk(&progress.id, progress.done, &buf[..read]))
+            .await
+            .map_err(|_| "соединение прервано")?;
+        progress.done += read as u64;
+
+        if progress.percent() != reported {
+            reported = progress.percent();
+            let _ = user_tx.send(NetEvent::Transfer(progress.clone())).await;
+        }
+    }
+    Ok(())
+}
+
+fn percent(done: u64, total: u64) -> u64 {
+    // пустой файл считаем переданным целиком
+    (done * 100).checked_div(total).unwrap_or(100)
+}
+
+// бинарный кадр: длина id (1 байт), id, смещение (8 байт, big-endian), данные
+pub fn encode_chunk(id: &str, offset: u64, data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(1 + id.len() + 8 + data.len());
+    chunk.push(id.len() as u8);
+    chunk.extend_from_slice(id.as_bytes());
+    chunk.extend_from_slice(&offset.to_be_bytes());
+    chunk.extend_from_slice(data);
+    chunk
+}
+
+pub fn decode_chunk(chunk: &[u8]) -> Option<(&str, u64, &[u8])> {
+    let (&id_len, rest) = chunk.split_first()?;
+    let (id, rest) = rest.split_at_checked(id_len as usize)?;
+    let (offset, data) = rest.split_at_checked(8)?;
+    let id = std::str::from_utf8(id).ok()?;
+    let offset = u64::from_be_bytes(offset.try_into().ok()?);
+    Some((id, offset, data))
+}
+
+fn hash_file(path: &Path) -> io::Result<(u64, String)> {
+    let mut file = File::open(path)?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Err(io::Error::other(format!("{} — не файл", path.display())));
+    }
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok((metadata.len(), hex::encode(hasher.finalize())))
+}
+
+// кусок, скачанный раньше, хешируем заново — без этого не проверить файл целиком
+fn open_part(part: PathBuf, size: u64) -> io::Result<Download> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .append(true)
+        .open(&part)?;
+    if file.metadata()?.len() > size {
+        file.set_len(0)?;
+    }
+
+    let mut hasher = Sha256::new();
+    let mut received = 0;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        received += read as u64;
+    }
+
+    Ok(Download {
+        file: Arc::new(Mutex::new(file)),
+        part,
+        hasher,
+        received,
+    })
+}
+
+// имя из предложения — только последняя часть пути, чтобы не выйти из папки загрузок
+fn safe_name(name: &str) -> String {
+    Path::new(name)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "file".to_string())
+}
+
+// существующие файлы не перезаписываем: name (1).ext, name (2).ext, ...
+fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+    let original = Path::new(name);
+    let stem = original
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let extension = original.extension().map(|ext| ext.to_string_lossy());
+    (1..)
+        .map(|n| match &extension {
+            Some(ext) => dir.join(format!("{} ({}).{}", stem, n, ext)),
+            None => dir.join(format!("{} ({})", stem, n)),
+        })
+        .find(|path| !path.exists())
+        .unwrap()
+}
@@ -0,0 +1,532 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::models::message::Message;
+
+const HISTORY_FILE: &str = "history.db";
+// версия схемы в PRAGMA user_version
+const SCHEMA_VERSION: i64 = 7;
+// личные переписки хранятся под "@собеседник" и в синхронизацию не попадают
+const NOT_DIRECT: &str = "conversation NOT LIKE '@%'";
+
+// место сообщения в истории отвечающего: (ts, logical, seq)
+pub type SyncCursor = (i64, i64, i64);
+
+// историю читают и уи, и сетевой слой при синхронизации
+pub type SharedHistory = Arc<Mutex<History>>;
+
+// сохранённое сообщение и то, как мы ему доверяли при получении
+pub struct StoredMessage {
+    pub message: Message,
+    pub untrusted: bool,
+}
+
+// история переписки в sqlite; сообщение хранится целиком в json,
+// порядок — по отметке HLC (ts, logical), при равенстве по порядку записи
+pub struct History {
+    conn: Connection,
+}
+
+impl History {
+    pub fn open(config_dir: &Path) -> rusqlite::Result<Self> {
+        Self::with_connection(Connection::open(config_dir.join(HISTORY_FILE))?)
+    }
+
+    fn with_connection(conn: Connection) -> rusqlite::Result<Self> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS messages (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                id TEXT NOT NULL UNIQUE,
+                conversation TEXT NOT NULL,
+                body TEXT NOT NULL,
+                untrusted INTEGER NOT NULL DEFAULT 0
+            );",
+        )?;
+        let history = Self { conn };
+        history.migrate()?;
+        Ok(history)
+    }
+
+    fn migrate(&self) -> rusqlite::Result<()> {
+        let version: i64 = self
+            .conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version >= SCHEMA_VERSION {
+            return Ok(());
+        }
+
+        // v2: отправитель и время вынесены в колонки для синхронизации и сортировки
+        if version < 2 {
+            self.conn.execute_batch(
+                "ALTER TABLE messages ADD COLUMN sender TEXT NOT NULL DEFAULT '';
+                 ALTER TABLE messages ADD COLUMN ts INTEGER NOT NULL DEFAULT 0;
+                 DROP INDEX IF EXISTS messages_conversation;
+                 CREATE INDEX messages_sender ON messages (sender, ts);",
+            )?;
+        }
+        // v3: логическая часть HLC
+        if version < 3 {
+            self.conn.execute_batch(
+                "ALTER TABLE messages ADD COLUMN logical INTEGER NOT NULL DEFAULT 0;
+                 DROP INDEX IF EXISTS messages_order;
+                 CREATE INDEX messages_order ON messages (conversation, ts, logical, seq);",
+            )?;
+        }
+        // v4: комнаты, в которых состоит пользователь; разговор сообщения — его комната
+        if version < 4 {
+            self.conn
+                .execute_batch("CREATE TABLE IF NOT EXISTS rooms (name TEXT PRIMARY KEY);")?;
+        }
+        // v5: реакции; снятая реакция остаётся строкой с on = 0, чтобы старая версия её не вернула
+        if version < 5 {
+            self.conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS reactions (
+                    message_id TEXT NOT NULL,
+                    username TEXT NOT NULL,
+                    emoji TEXT NOT NULL,
+                    active INTEGER NOT NULL,
+                    at INTEGER NOT NULL,
+                    PRIMARY KEY (message_id, username, emoji)
+                );",
+            )?;
+        }
+        // v6: время последней правки или удаления — чтобы синхронизация досылала и их
+        if version < 6 {
+            self.conn.execute_batch(
+                "ALTER TABLE messages ADD COLUMN updated INTEGER NOT NULL DEFAULT 0;",
+            )?;
+        }
+        // v7: реакции привязаны к ключу автора, а не к имени; старые, неподписанные, не переносим
+        if version < 7 {
+            self.conn.execute_batch(
+                "DROP TABLE IF EXISTS reactions;
+                 CREATE TABLE reactions (
+                    message_id TEXT NOT NULL,
+                    public_key TEXT NOT NULL,
+                    emoji TEXT NOT NULL,
+                    active INTEGER NOT NULL,
+                    at INTEGER NOT NULL,
+                    PRIMARY KEY (message_id, public_key, emoji)
+                );",
+            )?;
+        }
+
+        let rows: Vec<(i64, String)> = {
+            let mut stmt = self.conn.prepare("SELECT seq, body FROM messages")?;
+            stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<rusqlite::Result<_>>()?
+        };
+        for (seq, body) in rows {
+            if let Ok(message) = serde_json::from_str::<Message>(&body) {
+                let (ts, logical) = message.order_key();
+                self.conn.execute(
+                    "UPDATE messages SET sender = ?1, ts = ?2, logical = ?3, updated = ?4
+                     WHERE seq = ?5",
+                    params![message.sender, ts, logical, message.updated_at(), seq],
+                )?;
+            }
+        }
+
+        self.conn
+            .execute_batch(&format!("PRAGMA user_version = {}", SCHEMA_VERSION))
+    }
+
+    // повтор с тем же id молча игнорируется
+    pub fn save(
+        &self,
+        conversation: &str,
+        message: &Message,
+        untrusted: bool,
+    ) -> rusqlite::Result<()> {
+        let body = serde_json::to_string(message).unwrap();
+        let (ts, logical) = message.order_key();
+        self.conn.execute(
+            "INSERT OR IGNORE INTO messages (id, conversation, body, untrusted, sender, ts, logical, updated)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                message.id,
+                conversation,
+                body,
+                untrusted,
+                message.sender,
+                ts,
+                logical,
+                message.updated_at()
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> rusqlite::Result<Option<StoredMessage>> {
+        let row = self
+            .conn
+            .query_row(
+                "SELECT body, untrusted FROM messages WHERE id = ?1",
+                [id],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, bool>(1)?)),
+            )
+            .optional()?;
+        Ok(row.and_then(|(body, untrusted)| {
+            let message = serde_json::from_str(&body).ok()?;
+            Some(StoredMessage { message, untrusted })
+        }))
+    }
+
+    // правка и удаление меняют только тело и время обновления: место в ленте и переписка
+    // остаются прежними
+    pub fn replace(&self, message: &Message) -> rusqlite::Result<()> {
+        let body = serde_json::to_string(message).unwrap();
+        self.conn.execute(
+            "UPDATE messages SET body = ?1, updated = ?2 WHERE id = ?3",
+            params![body, message.updated_at(), message.id],
+        )?;
+        Ok(())
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.position_of(id).is_some()
+    }
+
+    fn position_of(&self, id: &str) -> Option<(i64, i64, i64)> {
+        self.conn
+            .query_row(
+                "SELECT ts, logical, seq FROM messages WHERE id = ?1",
+                [id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()
+            .ok()
+            .flatten()
+    }
+
+    // последние limit сообщений перед before (или самые свежие), от старых к новым
+    pub fn load_page(
+        &self,
+        conversation: &str,
+        before: Option<&str>,
+        limit: usize,
+    ) -> rusqlite::Result<Vec<StoredMessage>> {
+        let (ts, logical, seq) = match before {
+            Some(id) => match self.position_of(id) {
+                Some(position) => position,
+                None => return Ok(Vec::new()),
+            },
+            None => (i64::MAX, i64::MAX, i64::MAX),
+        };
+
+        let mut stmt = self.conn.prepare(
+            "SELECT body, untrusted FROM messages
+             WHERE conversation = ?1 AND (ts, logical, seq) < (?2, ?3, ?4)
+             ORDER BY ts DESC, logical DESC, seq DESC LIMIT ?5",
+        )?;
+        let params = params![conversation, ts, logical, seq, limit as i64];
+        let rows = stmt.query_map(params, |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, bool>(1)?))
+        })?;
+
+        let mut page = Vec::new();
+        for row in rows {
+            let (body, untrusted) = row?;
+            // запись, которую не получилось разобрать, пропускаем, а не роняем историю
+            if let Ok(message) = serde_json::from_str(&body) {
+                page.push(StoredMessage { message, untrusted });
+            }
+        }
+        page.reverse();
+        Ok(page)
+    }
+
+    // ветка: корень root и все ответы, цепочкой ведущие к нему, от старых к новым;
+    // ответ всегда позже того, на что отвечает, поэтому хватает одного прохода от корня
+    pub fn thread(&self, conversation: &str, root: &str) -> rusqlite::Result<Vec<StoredMessage>> {
+        let Some((ts, logical, seq)) = self.position_of(root) else {
+            return Ok(Vec::new());
+        };
+
+        let mut stmt = self.conn.prepare(
+            "SELECT body, untrusted FROM messages
+             WHERE conversation = ?1 AND (ts, logical, seq) >= (?2, ?3, ?4)
+             ORDER BY ts, logical, seq",
+        )?;
+        let rows = stmt.query_map(params![conversation, ts, logical, seq], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, bool>(1)?))
+        })?;
+
+        let mut ids = HashSet::from([root.to_string()]);
+        let mut thread = Vec::new();
+        for row in rows {
+            let (body, untrusted) = row?;
+            let Ok(message) = serde_json::from_str::<Message>(&body) else {
+                continue;
+            };
+            let in_thread = message.id == root
+                || message
+                    .reply_to
+                    .as_ref()
+                    .is_some_and(|parent| ids.contains(parent));
+            if in_thread {
+                ids.insert(message.id.clone());
+                thread.push(StoredMessage { message, untrusted });
+            }
+        }
+        Ok(thread)
+    }
+
+    // самое свежее время по каждому автору — то, что мы уже видели; неподтверждённое
+    // не в счёт, иначе самозванец под чужим именем сдвинул бы отметку настоящего автора
+    pub fn watermarks(&self) -> rusqlite::Result<BTreeMap<String, i64>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT sender, MAX(ts) FROM messages WHERE {} AND untrusted = 0 GROUP BY sender",
+            NOT_DIRECT
+        ))?;
+        stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect()
+    }
+
+    // то же для правок и удалений: отдельно, иначе свежая правка скрыла бы пропущенные
+    // сообщения того же автора
+    pub fn update_marks(&self) -> rusqlite::Result<BTreeMap<String, i64>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT sender, MAX(updated) FROM messages
+             WHERE {} AND untrusted = 0 AND updated > 0 GROUP BY sender",
+            NOT_DIRECT
+        ))?;
+        stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect()
+    }
+
+    // чего нет у собеседника: сообщения не старше его отметки по автору и правки с удалениями
+    // не старше отметки правок (равные включаем — в одну миллисекунду могло уйти несколько,
+    // дубли отсекаются по id); удалённые уходят надгробиями.
+    // от старых к новым, начиная после позиции after; если влезло не всё, вместе с пачкой
+    // возвращается позиция последнего отданного — с неё собеседник попросит продолжение
+    pub fn since(
+        &self,
+        watermarks: &BTreeMap<String, i64>,
+        updates: &BTreeMap<String, i64>,
+        after: Option<SyncCursor>,
+        limit: usize,
+    ) -> rusqlite::Result<(Vec<Message>, Option<SyncCursor>)> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT sender, ts, logical, seq, updated, body FROM messages WHERE {}
+             ORDER BY ts, logical, seq",
+            NOT_DIRECT
+        ))?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                (row.get(1)?, row.get(2)?, row.get(3)?),
+                row.get::<_, i64>(4)?,
+                row.get::<_, String>(5)?,
+            ))
+        })?;
+
+        let mut missing = Vec::new();
+        let mut last = None;
+        for row in rows {
+            let (sender, position, updated, body): (_, SyncCursor, _, _) = row?;
+            if after.is_some_and(|after| position <= after) {
+                continue;
+            }
+            let (ts, _, _) = position;
+            let new = watermarks.get(&sender).is_none_or(|&seen| ts >= seen);
+            let changed = updated > 0 && updates.get(&sender).is_none_or(|&seen| updated >= seen);
+            if !new && !changed {
+                continue;
+            }
+            if missing.len() == limit {
+                return Ok((missing, last));
+            }
+            if let Ok(message) = serde_json::from_str(&body) {
+                missing.push(message);
+                last = Some(position);
+            }
+        }
+        Ok((missing, None))
+    }
+
+    // false — у нас уже есть версия этой реакции не старше, ничего не поменялось
+    pub fn save_reaction(
+        &self,
+        id: &str,
+        public_key: &str,
+        emoji: &str,
+        on: bool,
+        at: i64,
+    ) -> rusqlite::Result<bool> {
+        let changed = self.conn.execute(
+            "INSERT INTO reactions (message_id, public_key, emoji, active, at)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (message_id, public_key, emoji)
+             DO UPDATE SET active = excluded.active, at = excluded.at WHERE excluded.at > reactions.at",
+            params![id, public_key, emoji, on, at],
+        )?;
+        Ok(changed > 0)
+    }
+
+    // время последней версии реакции — новая должна быть позже, иначе её не примут
+    pub fn reaction_at(
+        &self,
+        id: &str,
+        public_key: &str,
+        emoji: &str,
+    ) -> rusqlite::Result<Option<i64>> {
+        self.conn
+            .query_row(
+                "SELECT at FROM reactions WHERE message_id = ?1 AND public_key = ?2 AND emoji = ?3",
+                params![id, public_key, emoji],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    // действующие реакции на сообщения переписки: (id сообщения, ключ автора, эмодзи)
+    // в порядке постановки
+    pub fn reactions(&self, conversation: &str) -> rusqlite::Result<Vec<(String, String, String)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT r.message_id, r.public_key, r.emoji FROM reactions r
+             JOIN messages m ON m.id = r.message_id
+             WHERE m.conversation = ?1 AND r.active = 1
+             ORDER BY r.at",
+        )?;
+        stmt.query_map([conversation], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?
+        .collect()
+    }
+
+    // комнаты, в которые пользователь вошёл, — чтобы после перезапуска они остались в списке
+    pub fn rooms(&self) -> rusqlite::Result<Vec<String>> {
+        let mut stmt = self.conn.prepare("SELECT name FROM rooms ORDER BY name")?;
+        stmt.query_map([], |row| row.get(0))?.collect()
+    }
+
+    pub fn join_room(&self, name: &str) -> rusqlite::Result<()> {
+        self.conn
+            .execute("INSERT OR IGNORE INTO rooms (name) VALUES (?1)", [name])?;
+        Ok(())
+    }
+
+    // история комнаты остаётся: при повторном входе она снова будет видна
+    pub fn leave_room(&self, name: &str) -> rusqlite::Result<()> {
+        self.conn
+            .execute("DELETE FROM rooms WHERE name = ?1", [name])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::clock::Hlc;
+    use crate::models::message::EDITED;
+
+    fn memory() -> History {
+        History::with_connection(Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    fn message(sender: &str, wall: i64) -> Message {
+        let mut message = Message::new(
+            sender.to_string(),
+            format!("в {}", wall),
+            Default::default(),
+        );
+        message.clock = Some(Hlc { wall, logical: 0 });
+        message
+    }
+
+    fn edited(mut message: Message, at: &str) -> Message {
+        message.metadata.insert(EDITED.to_string(), at.to_string());
+        message
+    }
+
+    fn contents(messages: &[Message]) -> Vec<&str> {
+        messages.iter().map(|m| m.content.as_str()).collect()
+    }
+
+    #[test]
+    fn watermarks_ignore_untrusted_and_direct_messages() {
+        let history = memory();
+        history
+            .save("general", &message("alice", 1000), false)
+            .unwrap();
+        history
+            .save("general", &message("alice", 5000), true)
+            .unwrap();
+        history.save("@bob", &message("bob", 3000), false).unwrap();
+
+        let watermarks = history.watermarks().unwrap();
+        assert_eq!(watermarks, BTreeMap::from([("alice".to_string(), 1000)]));
+    }
+
+    #[test]
+    fn update_marks_track_latest_trusted_change() {
+        let history = memory();
+        let original = message("alice", 1000);
+        history.save("general", &original, false).unwrap();
+        assert!(history.update_marks().unwrap().is_empty());
+
+        history
+            .replace(&edited(original, "2024-01-01T00:00:02.000Z"))
+            .unwrap();
+        // досланное синхронизацией сразу сохраняется с временем правки
+        let backfilled = edited(message("alice", 1500), "2024-01-01T00:00:03.000Z");
+        history.save("general", &backfilled, false).unwrap();
+        let spoofed = edited(message("alice", 1600), "2024-01-01T00:00:09.000Z");
+        history.save("general", &spoofed, true).unwrap();
+
+        let marks = history.update_marks().unwrap();
+        assert_eq!(
+            marks,
+            BTreeMap::from([("alice".to_string(), 1_704_067_203_000)])
+        );
+    }
+
+    #[test]
+    fn since_includes_equal_marks_and_later_changes() {
+        let history = memory();
+        history
+            .save("general", &message("alice", 1000), false)
+            .unwrap();
+        history
+            .save("general", &message("alice", 2000), false)
+            .unwrap();
+        let old_edit = edited(message("alice", 500), "2024-01-01T00:00:01.000Z");
+        history.save("general", &old_edit, false).unwrap();
+        history
+            .save("general", &message("bob", 100), false)
+            .unwrap();
+
+        let watermarks = BTreeMap::from([("alice".to_string(), 2000)]);
+        let updates = BTreeMap::from([("alice".to_string(), 1_704_067_201_000)]);
+        let (missing, next) = history.since(&watermarks, &updates, None, 10).unwrap();
+        assert_eq!(contents(&missing), ["в 100", "в 500", "в 2000"]);
+        assert_eq!(next, None);
+
+        let updates = BTreeMap::from([("alice".to_string(), 1_704_067_202_000)]);
+        let (missing, _) = history.since(&watermarks, &updates, None, 10).unwrap();
+        assert_eq!(contents(&missing), ["в 100", "в 2000"]);
+    }
+
+    #[test]
+    fn since_sends_oldest_first_and_continues_after_cursor() {
+        let history = memory();
+        for wall in [5000, 1000, 3000, 2000, 4000] {
+            history
+                .save("general", &message("alice", wall), false)
+                .unwrap();
+        }
+        let none = BTreeMap::new();
+
+        let (first, next) = history.since(&none, &none, None, 2).unwrap();
+        assert_eq!(contents(&first), ["в 1000", "в 2000"]);
+        let (second, next) = history.since(&none, &none, next, 2).unwrap();
+        assert_eq!(contents(&second), ["в 3000", "в 4000"]);
+        let (third, next) = history.since(&none, &none, next, 2).unwrap();
+        assert_eq!(contents(&third), ["в 5000"]);
+        assert_eq!(next, None);
+    }
+}
@@ -0,0 +1,126 @@
+use super::{Arg, Command, CommandContext, CommandRegistry, CommandResult};
+use crate::models::message::MessageKind;
+use crate::models::reaction;
+use crate::ui::events::{self, UiEvent};
+
+pub fn register(registry: &mut CommandRegistry) {
+    registry.register(Command {
+        name: "help",
+        args: vec![Arg::optional("команда")],
+        help: "список команд или справка по одной",
+        run: help,
+    });
+    registry.register(Command {
+        name: "nick",
+        args: vec![Arg::required("имя")],
+        help: "сменить имя до конца сеанса",
+        run: nick,
+    });
+    registry.register(Command {
+        name: "me",
+        args: vec![Arg::required("действие").rest()],
+        help: "написать от третьего лица: * имя действие",
+        run: me,
+    });
+    registry.register(Command {
+        name: "delete",
+        args: vec![Arg::optional("id")],
+        help: "удалить своё сообщение, по умолчанию последнее",
+        run: delete,
+    });
+    registry.register(Command {
+        name: "react",
+        args: vec![Arg::required("эмодзи"), Arg::optional("id")],
+        help: "поставить или снять реакцию (:+1:, :heart:, …), по умолчанию на последнее сообщение",
+        run: react,
+    });
+    registry.register(Command {
+        name: "clear",
+        args: vec![],
+        help: "очистить ленту (история остаётся)",
+        run: clear,
+    });
+    registry.register(Command {
+        name: "connect",
+        args: vec![Arg::required("host:port")],
+        help: "подключиться к узлу",
+        run: connect,
+    });
+    registry.register(Command {
+        name: "quit",
+        args: vec![],
+        help: "выйти из чата",
+        run: |_| Ok(Some(UiEvent::Quit)),
+    });
+}
+
+fn delete(context: &mut CommandContext<'_>) -> CommandResult {
+    let id = context.arg(0).map(str::to_string);
+    context.state.delete_message(id.as_deref())?;
+    Ok(None)
+}
+
+fn react(context: &mut CommandContext<'_>) -> CommandResult {
+    let input = context.arg(0).unwrap_or_default();
+    let emoji = reaction::parse(input).ok_or_else(|| {
+        let codes: Vec<String> = reaction::shortcodes()
+            .map(|(code, _)| format!(":{}:", code))
+            .collect();
+        format!("{} — не эмодзи; коды: {}", input, codes.join(" "))
+    })?;
+    let id = context.arg(1).map(str::to_string);
+    context.state.react(emoji, id)?;
+    Ok(None)
+}
+
+fn help(context: &mut CommandContext<'_>) -> CommandResult {
+    let lines: Vec<String> = match context.arg(0) {
+        Some(name) => {
+            let command = context
+                .registry
+                .get(name)
+                .ok_or_else(|| format!("нет команды {}", name))?;
+            vec![format!("{} — {}", command.usage(), command.help)]
+        }
+        None => context
+            .registry
+            .iter()
+            .map(|command| format!("{} — {}", command.usage(), command.help))
+            .collect(),
+    };
+    for line in lines {
+        context.state.add_notice(line);
+    }
+    Ok(None)
+}
+
+fn nick(context: &mut CommandContext<'_>) -> CommandResult {
+    let name = context.arg(0).unwrap_or_default().to_string();
+    context.state.rename(&name)?;
+    Ok(Some(UiEvent::Rename(name)))
+}
+
+fn me(context: &mut CommandContext<'_>) -> CommandResult {
+    let text = context.arg(0).unwrap_or_default().to_string();
+    Ok(Some(events::compose(
+        context.state,
+        text,
+        MessageKind::Action,
+    )))
+}
+
+fn clear(context: &mut CommandContext<'_>) -> CommandResult {
+    context.state.clear_feed();
+    Ok(None)
+}
+
+fn connect(context: &mut CommandContext<'_>) -> CommandResult {
+    let addr = context.arg(0).unwrap_or_default();
+    let valid = addr
+        .rsplit_once(':')
+        .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+    if !valid {
+        return Err(format!("адрес должен быть вида host:port, а не {}", addr));
+    }
+    Ok(Some(UiEvent::Dial(addr.to_string())))
+}
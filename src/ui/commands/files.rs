@@ -0,0 +1,29 @@
+use std::path::PathBuf;
+
+use super::{Arg, Command, CommandContext, CommandRegistry, CommandResult};
+use crate::ui::events::UiEvent;
+
+pub fn register(registry: &mut CommandRegistry) {
+    registry.register(Command {
+        name: "send",
+        args: vec![Arg::required("путь к файлу").rest()],
+        help: "предложить файл собеседникам",
+        run: send,
+    });
+    registry.register(Command {
+        name: "accept",
+        args: vec![Arg::required("id файла")],
+        help: "принять предложенный файл",
+        run: accept,
+    });
+}
+
+fn send(context: &mut CommandContext<'_>) -> CommandResult {
+    let path = context.arg(0).unwrap_or_default();
+    Ok(Some(UiEvent::SendFile(PathBuf::from(path))))
+}
+
+fn accept(context: &mut CommandContext<'_>) -> CommandResult {
+    let id = context.arg(0).unwrap_or_default();
+    Ok(Some(UiEvent::AcceptFile(id.to_string())))
+}
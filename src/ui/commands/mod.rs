@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+
+use super::events::UiEvent;
+use super::state::UiState;
+
+mod chat;
+mod files;
+mod presence;
+mod rooms;
+
+// Ok(None) — команда выполнена на месте, Err — текст ошибки для ленты
+pub type CommandResult = Result<Option<UiEvent>, String>;
+pub type Handler = fn(&mut CommandContext<'_>) -> CommandResult;
+
+// описание аргумента: по нему проверяем ввод и собираем строку использования
+#[derive(Debug, Clone, Copy)]
+pub struct Arg {
+    name: &'static str,
+    required: bool,
+    rest: bool,
+}
+
+impl Arg {
+    pub const fn required(name: &'static str) -> Self {
+        Self {
+            name,
+            required: true,
+            rest: false,
+        }
+    }
+
+    pub const fn optional(name: &'static str) -> Self {
+        Self {
+            name,
+            required: false,
+            rest: false,
+        }
+    }
+
+    // забирает остаток строки вместе с пробелами — для текста и путей
+    pub const fn rest(self) -> Self {
+        Self { rest: true, ..self }
+    }
+}
+
+pub struct Command {
+    pub name: &'static str,
+    pub args: Vec<Arg>,
+    pub help: &'static str,
+    pub run: Handler,
+}
+
+impl Command {
+    // "/msg <пользователь> [текст]"
+    pub fn usage(&self) -> String {
+        let mut usage = format!("/{}", self.name);
+        for arg in &self.args {
+            let name = if arg.required {
+                format!(" <{}>", arg.name)
+            } else {
+                format!(" [{}]", arg.name)
+            };
+            usage.push_str(&name);
+        }
+        usage
+    }
+
+    // аргументы по описанию: лишние и недостающие — ошибка
+    fn parse<'a>(&self, input: &'a str) -> Result<Vec<&'a str>, String> {
+        let mut args = Vec::new();
+        let mut rest = input.trim();
+        for arg in &self.args {
+            if rest.is_empty() {
+                if arg.required {
+                    return Err(format!("не хватает аргумента <{}>", arg.name));
+                }
+                break;
+            }
+            if arg.rest {
+                args.push(rest);
+                rest = "";
+                break;
+            }
+            let (value, tail) = rest.split_once(' ').unwrap_or((rest, ""));
+            args.push(value);
+            rest = tail.trim_start();
+        }
+        if !rest.is_empty() {
+            return Err(format!("лишние аргументы: {}", rest));
+        }
+        Ok(args)
+    }
+}
+
+// всё, что видит обработчик команды
+pub struct CommandContext<'a> {
+    pub state: &'a mut UiState,
+    pub registry: &'a CommandRegistry,
+    args: Vec<&'a str>,
+}
+
+impl CommandContext<'_> {
+    pub fn arg(&self, index: usize) -> Option<&str> {
+        self.args.get(index).copied()
+    }
+}
+
+// реестр команд строки ввода; каждый модуль добавляет свои через register
+pub struct CommandRegistry {
+    commands: BTreeMap<&'static str, Command>,
+}
+
+impl Default for CommandRegistry {
+    fn default() -> Self {
+        let mut registry = Self::new();
+        chat::register(&mut registry);
+        rooms::register(&mut registry);
+        files::register(&mut registry);
+        presence::register(&mut registry);
+        registry
+    }
+}
+
+impl CommandRegistry {
+    pub fn new() -> Self {
+        Self {
+            commands: BTreeMap::new(),
+        }
+    }
+
+    pub fn register(&mut self, command: Command) {
+        self.commands.insert(command.name, command);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Command> {
+        self.commands.get(name.trim_start_matches('/'))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Command> {
+        self.commands.values()
+    }
+
+    // ошибки разбора и выполнения пишем в ленту, в сеть уходит только результат
+    pub fn dispatch(&self, state: &mut UiState, input: &str) -> Option<UiEvent> {
+        let input = input.trim().trim_start_matches('/');
+        let (name, rest) = input.split_once(' ').unwrap_or((input, ""));
+        let Some(command) = self.commands.get(name) else {
+            state.add_notice(format!(
+                "Неизвестная команда /{}, список команд: /help",
+                name
+            ));
+            return None;
+        };
+
+        let args = match command.parse(rest) {
+            Ok(args) => args,
+            Err(e) => {
+                state.add_notice(format!(
+                    "/{}: {}. Использование: {}",
+                    name,
+                    e,
+                    command.usage()
+                ));
+                return None;
+            }
+        };
+        let mut context = CommandContext {
+            state,
+            registry: self,
+            args,
+        };
+        match (command.run)(&mut context) {
+            Ok(event) => event,
+            Err(e) => {
+                context.state.add_notice(format!("/{}: {}", name, e));
+                None
+            }
+        }
+    }
+
+    // имена команд, начинающиеся с prefix
+    pub fn complete(&self, prefix: &str) -> Vec<&'static str> {
+        let prefix = prefix.trim_start_matches('/');
+        self.commands
+            .keys()
+            .copied()
+            .filter(|name| name.starts_with(prefix))
+            .collect()
+    }
+}
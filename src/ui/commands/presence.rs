@@ -0,0 +1,32 @@
+use super::{Command, CommandContext, CommandRegistry, CommandResult};
+
+pub fn register(registry: &mut CommandRegistry) {
+    registry.register(Command {
+        name: "away",
+        args: vec![],
+        help: "отметиться отошедшим",
+        run: away,
+    });
+    registry.register(Command {
+        name: "back",
+        args: vec![],
+        help: "вернуться (любое сообщение тоже возвращает)",
+        run: back,
+    });
+}
+
+fn away(context: &mut CommandContext<'_>) -> CommandResult {
+    if !context.state.set_away(true) {
+        return Err("вы уже отошли".to_string());
+    }
+    context.state.add_notice("Вы отошли".to_string());
+    Ok(None)
+}
+
+fn back(context: &mut CommandContext<'_>) -> CommandResult {
+    if !context.state.set_away(false) {
+        return Err("вы и так на месте".to_string());
+    }
+    context.state.add_notice("Вы вернулись".to_string());
+    Ok(None)
+}
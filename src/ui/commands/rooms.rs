@@ -0,0 +1,56 @@
+use super::{Arg, Command, CommandContext, CommandRegistry, CommandResult};
+use crate::models::message::MessageKind;
+use crate::ui::events;
+
+pub fn register(registry: &mut CommandRegistry) {
+    registry.register(Command {
+        name: "join",
+        args: vec![Arg::required("комната")],
+        help: "войти в комнату или переключиться на неё",
+        run: join,
+    });
+    registry.register(Command {
+        name: "leave",
+        args: vec![Arg::optional("комната")],
+        help: "выйти из комнаты или закрыть личную переписку",
+        run: leave,
+    });
+    registry.register(Command {
+        name: "rooms",
+        args: vec![],
+        help: "список комнат и личных переписок",
+        run: rooms,
+    });
+    registry.register(Command {
+        name: "msg",
+        args: vec![Arg::required("пользователь"), Arg::optional("текст").rest()],
+        help: "личное сообщение; без текста — открыть переписку",
+        run: msg,
+    });
+}
+
+fn join(context: &mut CommandContext<'_>) -> CommandResult {
+    let room = context.arg(0).unwrap_or_default().to_string();
+    context.state.join_room(&room);
+    Ok(None)
+}
+
+fn leave(context: &mut CommandContext<'_>) -> CommandResult {
+    let room = context.arg(0).map(str::to_string);
+    context.state.leave_room(room.as_deref());
+    Ok(None)
+}
+
+fn rooms(context: &mut CommandContext<'_>) -> CommandResult {
+    context.state.list_rooms();
+    Ok(None)
+}
+
+fn msg(context: &mut CommandContext<'_>) -> CommandResult {
+    let user = context.arg(0).unwrap_or_default().to_string();
+    let text = context.arg(1).map(str::to_string);
+    if !context.state.open_direct(&user) {
+        return Ok(None);
+    }
+    Ok(text.map(|text| events::compose(context.state, text, MessageKind::Text)))
+}
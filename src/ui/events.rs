@@ -1,18 +1,34 @@
+use std::path::PathBuf;
+
 use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
 use tokio::sync::mpsc::Sender;
 
-use super::state::UiState;
-use crate::models::message::Message;
+use super::commands::CommandRegistry;
+use super::state::{InputMode, UiState};
+use crate::models::message::{Message, MessageKind};
+use crate::network::NetCommand;
+use crate::network::protocol::Frame;
 
 pub enum UiEvent {
-    SendMessage(Message),
+    SendMessage(Box<Message>),
+    // зашифрованное уходит в сеть, открытый текст — в свою ленту
+    SendDirect {
+        wire: Box<Message>,
+        local: Box<Message>,
+    },
+    Dial(String),
+    Rename(String),
+    SendFile(PathBuf),
+    AcceptFile(String),
     Quit,
     ScrollUp,
     ScrollDown,
     ScrollToBottom,
 }
 
-pub struct EventHandler;
+pub struct EventHandler {
+    commands: CommandRegistry,
+}
 
 impl Default for EventHandler {
     fn default() -> Self {
@@ -22,24 +38,60 @@ impl Default for EventHandler {
 
 impl EventHandler {
     pub fn new() -> Self {
-        Self
+        Self {
+            commands: CommandRegistry::default(),
+        }
     }
 
     pub async fn handle_key_event(
         &self,
         key_event: KeyEvent,
         state: &mut UiState,
-        net_tx: &Sender<Message>,
+        net_tx: &Sender<NetCommand>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         match self.process_key_event(key_event, state) {
             Some(UiEvent::SendMessage(message)) => {
-                if let Err(e) = net_tx.send(message.clone()).await {
-                    eprintln!("Ошибка отправки сообщения: {}", e);
+                if let Err(e) = net_tx
+                    .send(NetCommand::Send(Box::new(Frame::Chat(*message.clone()))))
+                    .await
+                {
+                    state.add_notice(format!("Ошибка отправки сообщения: {}", e));
+                } else {
+                    state.add_own_message(*message);
+                    state.clear_input();
+                }
+            }
+            Some(UiEvent::SendDirect { wire, local }) => {
+                if let Err(e) = net_tx
+                    .send(NetCommand::Send(Box::new(Frame::Direct(*wire))))
+                    .await
+                {
+                    state.add_notice(format!("Ошибка отправки сообщения: {}", e));
                 } else {
-                    state.add_message(message);
+                    state.add_own_message(*local);
                     state.clear_input();
                 }
             }
+            Some(UiEvent::Dial(addr)) => {
+                if let Err(e) = net_tx.send(NetCommand::Dial(addr)).await {
+                    state.add_notice(format!("Ошибка подключения: {}", e));
+                }
+            }
+            Some(UiEvent::Rename(name)) => {
+                if let Err(e) = net_tx.send(NetCommand::Rename(name)).await {
+                    state.add_notice(format!("Ошибка смены имени: {}", e));
+                }
+            }
+            Some(UiEvent::SendFile(path)) => {
+                if let Err(e) = net_tx.send(NetCommand::SendFile(path)).await {
+                    state.add_notice(format!("Ошибка отправки файла: {}", e));
+                }
+            }
+            Some(UiEvent::AcceptFile(id)) => {
+                if let Err(e) = net_tx.send(NetCommand::AcceptFile(id)).await {
+                    state.add_notice(format!("Ошибка приёма файла: {}", e));
+                }
+            }
             Some(UiEvent::Quit) => {
                 state.quit();
             }
@@ -58,6 +110,9 @@ impl EventHandler {
     }
 
     fn process_key_event(&self, key_event: KeyEvent, state: &mut UiState) -> Option<UiEvent> {
+        if matches!(state.input_mode, InputMode::Normal) {
+            return self.process_selection_key(key_event, state);
+        }
         match key_event.code {
             KeyCode::Char(c) => {
                 if key_event.modifiers.contains(KeyModifiers::CONTROL) {
@@ -72,13 +127,33 @@ impl EventHandler {
                 None
             }
             KeyCode::Enter => {
-                if !state.is_input_empty() {
-                    Some(UiEvent::SendMessage(state.create_message()))
-                } else {
+                if state.is_input_empty() {
                     None
+                } else if state.editing.is_some() && !state.get_input().starts_with('/') {
+                    let text = state.get_input().to_string();
+                    state.clear_input();
+                    state.finish_edit(text);
+                    None
+                } else if state.get_input().starts_with('/') {
+                    let input = state.get_input().to_string();
+                    state.clear_input();
+                    self.commands.dispatch(state, &input)
+                } else {
+                    let text = state.get_input().to_string();
+                    Some(compose(state, text, MessageKind::Text))
                 }
             }
-            KeyCode::Esc => Some(UiEvent::Quit),
+            KeyCode::Tab => {
+                self.complete_command(state);
+                None
+            }
+            // Esc по очереди отменяет правку, ответ, закрывает ветку и только потом выходит
+            KeyCode::Esc => {
+                let cancelled = state.cancel_edit() || state.cancel_reply() || state.close_thread();
+                (!cancelled).then_some(UiEvent::Quit)
+            }
+            // ↑ на пустой строке правит своё последнее сообщение, иначе — прокрутка
+            KeyCode::Up if state.is_input_empty() && state.start_edit() => None,
             KeyCode::Up => Some(UiEvent::ScrollUp),
             KeyCode::Down => Some(UiEvent::ScrollDown),
             KeyCode::End => Some(UiEvent::ScrollToBottom),
@@ -100,6 +175,67 @@ impl EventHandler {
         }
     }
 
+    // режим выбора сообщения: строка ввода не трогается
+    fn process_selection_key(&self, key_event: KeyEvent, state: &mut UiState) -> Option<UiEvent> {
+        if key_event.modifiers.contains(KeyModifiers::CONTROL) {
+            return match key_event.code {
+                KeyCode::Char('c') => Some(UiEvent::Quit),
+                KeyCode::Char('s') => {
+                    state.stop_selection();
+                    None
+                }
+                _ => None,
+            };
+        }
+        match key_event.code {
+            KeyCode::Up | KeyCode::Char('k') => state.move_selection(true),
+            KeyCode::Down | KeyCode::Char('j') => state.move_selection(false),
+            KeyCode::Enter | KeyCode::Char('r') => state.reply_to_selected(),
+            KeyCode::Char('t') => state.open_thread(),
+            KeyCode::Char('e') => state.react_to_selected(),
+            // + — быстрый 👍, повторное нажатие снимает его
+            KeyCode::Char('+') => {
+                let id = state.selected.clone();
+                if let Err(e) = state.react("👍".to_string(), id) {
+                    state.add_notice(format!("/react: {}", e));
+                }
+            }
+            KeyCode::Esc => state.stop_selection(),
+            _ => {}
+        }
+        None
+    }
+
+    // дополняем имя команды; при нескольких вариантах — до общего начала или списком
+    fn complete_command(&self, state: &mut UiState) {
+        let input = state.get_input();
+        if !input.starts_with('/') || input.contains(' ') {
+            return;
+        }
+        let candidates = self.commands.complete(input);
+        match candidates.as_slice() {
+            [] => {}
+            [name] => state.input = format!("/{} ", name),
+            [first, others @ ..] => {
+                let common = others.iter().fold(first.len(), |len, name| {
+                    first
+                        .bytes()
+                        .zip(name.bytes())
+                        .take(len)
+                        .take_while(|(a, b)| a == b)
+                        .count()
+                });
+                if common + 1 > input.len() {
+                    state.input = format!("/{}", &first[..common]);
+                } else {
+                    let list: Vec<String> =
+                        candidates.iter().map(|name| format!("/{}", name)).collect();
+                    state.add_notice(list.join("  "));
+                }
+            }
+        }
+    }
+
     fn handle_control_char(&self, c: char, state: &mut UiState) -> Option<UiEvent> {
         match c {
             'c' => Some(UiEvent::Quit), // Ctrl+C для выхода
@@ -112,6 +248,29 @@ impl EventHandler {
                 state.clear_input();
                 None
             }
+            's' => {
+                // Ctrl+S — выбрать сообщение, чтобы ответить на него или открыть ветку
+                if !state.start_selection() {
+                    state.add_notice("Выбирать пока нечего".to_string());
+                }
+                None
+            }
+            'n' => {
+                // Ctrl+N — следующий найденный в сети узел
+                state.select_next_peer();
+                None
+            }
+            'p' => {
+                // Ctrl+P — показать или спрятать список участников
+                state.toggle_participants();
+                None
+            }
+            'o' => {
+                // Ctrl+O — подключиться к выбранному узлу
+                state
+                    .selected_discovered()
+                    .map(|peer| UiEvent::Dial(peer.addr.clone()))
+            }
             _ => None,
         }
     }
@@ -120,7 +279,7 @@ impl EventHandler {
         &self,
         event: Event,
         state: &mut UiState,
-        net_tx: &Sender<Message>,
+        net_tx: &Sender<NetCommand>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         match event {
             Event::Key(key_event) => tokio::task::block_in_place(|| {
@@ -132,3 +291,17 @@ impl EventHandler {
         }
     }
 }
+
+// текст уходит в открытую переписку: личную или в комнату
+pub fn compose(state: &mut UiState, text: String, kind: MessageKind) -> UiEvent {
+    match state.direct_peer().map(str::to_string) {
+        Some(to) => {
+            let (wire, local) = state.create_direct(&to, text, kind);
+            UiEvent::SendDirect {
+                wire: Box::new(wire),
+                local: Box::new(local),
+            }
+        }
+        None => UiEvent::SendMessage(Box::new(state.create_message(text, kind))),
+    }
+}
@@ -1,3 +1,4 @@
+pub mod commands;
 pub mod events;
 pub mod renderer;
 pub mod state;
@@ -15,14 +16,16 @@ use ratatui::{Terminal, backend::CrosstermBackend};
 
 use std::io;
 use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::watch;
 
-use crate::models::message::Message;
+use crate::network::connection::PeerStates;
+use crate::network::{NetCommand, NetEvent};
 
 pub async fn run_ui(
-    mut user_rx: Receiver<Message>,
-    net_tx: Sender<Message>,
-    username: String,
-    token: String,
+    mut user_rx: Receiver<NetEvent>,
+    net_tx: Sender<NetCommand>,
+    mut states_rx: watch::Receiver<PeerStates>,
+    mut state: UiState,
 ) -> io::Result<()> {
     // инит терма
     enable_raw_mode()?;
@@ -32,23 +35,25 @@ pub async fn run_ui(
     let mut terminal = Terminal::new(backend)?;
 
     // инит компонентов интерфейса
-    let mut state = UiState::new(username, token);
     let event_handler = EventHandler::new();
     let renderer = UiRenderer::new();
 
     // цикл аппки
     loop {
         // рисуем уи
+        let mut visible_height = 0;
         terminal.draw(|frame| {
-            renderer.render(frame, &state);
+            visible_height = renderer.render(frame, &state);
         })?;
+        state.set_visible_height(visible_height);
+        state.mark_visible_read();
 
         // обрабатываем события
         if event::poll(std::time::Duration::from_millis(100))? {
             let event = event::read()?;
 
             if let Err(e) = event_handler.handle_crossterm_event(event, &mut state, &net_tx) {
-                eprintln!("Ошибка обработки события: {}", e);
+                state.add_notice(format!("Ошибка обработки события: {}", e));
             }
         }
 
@@ -58,8 +63,22 @@ pub async fn run_ui(
         }
 
         // обрабатываем входящие сообщения
-        while let Ok(msg) = user_rx.try_recv() {
-            state.add_message(msg);
+        while let Ok(event) = user_rx.try_recv() {
+            state.handle_net_event(event);
+        }
+        // квитанции, правки, удаления и реакции: реакции синхронизация не досылает,
+        // поэтому ждём места в очереди, а не выбрасываем кадр
+        for frame in state.take_outgoing() {
+            if net_tx
+                .send(NetCommand::Send(Box::new(frame)))
+                .await
+                .is_err()
+            {
+                state.add_notice("Сеть остановлена, кадр не отправлен".to_string());
+            }
+        }
+        if states_rx.has_changed().unwrap_or(false) {
+            state.peer_states = states_rx.borrow_and_update().clone();
         }
     }
 
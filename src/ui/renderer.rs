@@ -1,3 +1,7 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
 use ratatui::{
     Frame,
     layout::{Constraint, Direction, Layout, Rect},
@@ -6,8 +10,17 @@ use ratatui::{
     widgets::{Block, Borders, List, ListItem, Paragraph},
 };
 
-use super::state::UiState;
-use crate::models::message::Message;
+use super::state::{InputMode, UiState, conversation_label};
+use crate::identity::DIRECT_NONCE;
+use crate::models::message::{DeliveryState, Message, MessageKind};
+use crate::network::transfer;
+
+// ширина колонки со списком комнат
+const ROOMS_WIDTH: u16 = 20;
+// и со списком участников
+const PARTICIPANTS_WIDTH: u16 = 24;
+// сколько символов исходного сообщения показываем в цитате над ответом
+const QUOTE_WIDTH: usize = 60;
 
 pub struct UiRenderer;
 
@@ -22,12 +35,42 @@ impl UiRenderer {
         Self
     }
 
-    pub fn render(&self, frame: &mut Frame, state: &UiState) {
+    // возвращает, сколько сообщений помещается в ленту — по ним считаем прочитанное
+    pub fn render(&self, frame: &mut Frame, state: &UiState) -> usize {
         let chunks = self.create_layout(frame.area());
 
-        self.render_messages(frame, &chunks[0], state);
+        // слева список комнат, справа от чата — узлы из локальной сети и участники
+        let participants_width = if state.show_participants {
+            PARTICIPANTS_WIDTH
+        } else {
+            0
+        };
+        let columns = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([
+                Constraint::Length(ROOMS_WIDTH),
+                Constraint::Min(0),
+                Constraint::Length(participants_width),
+            ])
+            .split(chunks[0]);
+        self.render_rooms(frame, &columns[0], state);
+        if state.show_participants {
+            self.render_participants(frame, &columns[2], state);
+        }
+        if state.discovered.is_empty() {
+            self.render_messages(frame, &columns[1], state);
+        } else {
+            let columns = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Percentage(70), Constraint::Percentage(30)])
+                .split(columns[1]);
+            self.render_messages(frame, &columns[0], state);
+            self.render_discovered(frame, &columns[1], state);
+        }
         self.render_input(frame, &chunks[1], state);
         self.render_status_bar(frame, &chunks[2], state);
+
+        chunks[0].height.saturating_sub(2) as usize
     }
 
     fn create_layout(&self, area: Rect) -> Vec<Rect> {
@@ -46,12 +89,29 @@ impl UiRenderer {
         let visible_height = area.height.saturating_sub(2) as usize; // учет границ
         let visible_messages = state.get_visible_messages(visible_height);
 
-        let items: Vec<ListItem> = visible_messages
+        let mut items: Vec<ListItem> = visible_messages
             .iter()
-            .map(|msg| self.create_message_item(msg, &state.username))
+            .map(|msg| self.create_message_item(msg, state))
             .collect();
+        // у ответов над строкой есть цитата: лишнее срезаем сверху, чтобы свежее оставалось видно
+        let mut lines: usize = items.iter().map(ListItem::height).sum();
+        while lines > visible_height && items.len() > 1 {
+            lines -= items.remove(0).height();
+        }
 
-        let title = format!("Чат ({} сообщений)", state.messages.len());
+        let title = if state.thread.is_some() {
+            format!(
+                "{} — ветка ({} сообщений, ESC - назад)",
+                conversation_label(&state.current_room),
+                state.messages.len()
+            )
+        } else {
+            format!(
+                "{} ({} сообщений)",
+                conversation_label(&state.current_room),
+                state.messages.len()
+            )
+        };
         let messages_widget = List::new(items).block(
             Block::default()
                 .borders(Borders::ALL)
@@ -62,11 +122,15 @@ impl UiRenderer {
         frame.render_widget(messages_widget, *area);
     }
 
-    fn create_message_item(&self, msg: &Message, current_user: &str) -> ListItem {
+    fn create_message_item(&self, msg: &Message, state: &UiState) -> ListItem<'_> {
         let timestamp = self.format_timestamp(&msg.timestamp);
-        let is_own_message = msg.sender == current_user;
+        // своё сообщение определяем по ключу подписи, а не по имени
+        let is_own_message = state.is_own_message(msg);
+        let is_untrusted = state.untrusted.contains(&msg.id);
 
-        let sender_style = if is_own_message {
+        let sender_style = if is_untrusted {
+            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD)
+        } else if is_own_message {
             Style::default()
                 .fg(Color::Green)
                 .add_modifier(Modifier::BOLD)
@@ -83,34 +147,348 @@ impl UiRenderer {
         };
 
         let timestamp_str = format!("[{}] ", timestamp);
-        let sender_str = format!("{}: ", msg.sender);
+        let warning = if is_untrusted { "⚠ " } else { "" };
+
+        let mut spans = vec![Span::styled(
+            timestamp_str,
+            Style::default().fg(Color::DarkGray),
+        )];
+        match msg.kind {
+            // от удалённого остаётся только строка-надгробие на его месте
+            _ if msg.is_deleted() => {
+                spans.push(Span::styled(
+                    format!("{}{}: ", warning, msg.sender),
+                    sender_style,
+                ));
+                spans.push(Span::styled(
+                    "[сообщение удалено]",
+                    Style::default()
+                        .fg(Color::DarkGray)
+                        .add_modifier(Modifier::ITALIC),
+                ));
+            }
+            MessageKind::System => spans.push(Span::styled(
+                format!("— {}", msg.content),
+                Style::default()
+                    .fg(Color::DarkGray)
+                    .add_modifier(Modifier::ITALIC),
+            )),
+            MessageKind::Action => spans.push(Span::styled(
+                format!("* {}{} {}", warning, msg.sender, msg.content),
+                Style::default()
+                    .fg(Color::Magenta)
+                    .add_modifier(Modifier::ITALIC),
+            )),
+            MessageKind::Notice => {
+                spans.push(Span::styled(
+                    format!("{}{}: ", warning, msg.sender),
+                    sender_style,
+                ));
+                spans.push(Span::styled(
+                    msg.content.clone(),
+                    Style::default().fg(Color::Blue),
+                ));
+            }
+            MessageKind::File => {
+                spans.push(Span::styled(
+                    format!("{}{}: ", warning, msg.sender),
+                    sender_style,
+                ));
+                spans.push(Span::styled(
+                    format!("[файл] {}", msg.content),
+                    content_style,
+                ));
+            }
+            MessageKind::Text | MessageKind::Unknown => {
+                spans.push(Span::styled(
+                    format!("{}{}: ", warning, msg.sender),
+                    sender_style,
+                ));
+                spans.push(Span::styled(msg.content.clone(), content_style));
+            }
+        }
+        if msg.is_edited() && !msg.is_deleted() {
+            spans.push(Span::styled(
+                " (изменено)",
+                Style::default().fg(Color::DarkGray),
+            ));
+        }
+        match state.delivery.get(&msg.id) {
+            Some(DeliveryState::Pending) => spans.push(Span::styled(
+                " (в очереди)",
+                Style::default().fg(Color::DarkGray),
+            )),
+            Some(DeliveryState::Sent) => {
+                spans.push(Span::styled(" ✓", Style::default().fg(Color::DarkGray)))
+            }
+            Some(DeliveryState::Delivered) => {
+                spans.push(Span::styled(" ✓✓", Style::default().fg(Color::DarkGray)))
+            }
+            Some(DeliveryState::Read) => {
+                spans.push(Span::styled(" ✓✓", Style::default().fg(Color::Cyan)))
+            }
+            None => {}
+        }
+        // личное сообщение шло зашифрованным для собеседника
+        if msg.metadata.contains_key(DIRECT_NONCE) {
+            spans.push(Span::styled(" 🔒", Style::default().fg(Color::DarkGray)));
+        }
+
+        let mut lines = Vec::new();
+        if msg.reply_to.is_some() {
+            lines.push(self.create_quote(msg, state));
+        }
+        lines.push(Line::from(spans));
+        if let Some(reactions) = state.reactions.get(&msg.id)
+            && !msg.is_deleted()
+        {
+            lines.push(self.create_reactions(reactions, state));
+        }
+        let item = ListItem::new(lines);
+        if state.selected.as_deref() == Some(msg.id.as_str()) {
+            item.style(Style::default().bg(Color::DarkGray))
+        } else {
+            item
+        }
+    }
+
+    // счётчики реакций под сообщением; свои выделены
+    fn create_reactions(
+        &self,
+        reactions: &BTreeMap<String, BTreeSet<String>>,
+        state: &UiState,
+    ) -> Line<'_> {
+        let own_key = state.identity.public_key();
+        let mut spans = vec![Span::raw("           ")];
+        for (emoji, users) in reactions {
+            let style = if users.contains(&own_key) {
+                Style::default()
+                    .fg(Color::Cyan)
+                    .add_modifier(Modifier::BOLD)
+            } else {
+                Style::default().fg(Color::DarkGray)
+            };
+            spans.push(Span::styled(format!("{} {}", emoji, users.len()), style));
+            spans.push(Span::raw("  "));
+        }
+        Line::from(spans)
+    }
+
+    // цитата над ответом: автор и начало исходного сообщения
+    fn create_quote(&self, msg: &Message, state: &UiState) -> Line<'_> {
+        let quote = match state.reply_target(msg) {
+            Some(target) if target.is_deleted() => {
+                format!("{}: [сообщение удалено]", target.sender)
+            }
+            Some(target) => format!("{}: {}", target.sender, preview(&target.content)),
+            None => "[исходное сообщение не найдено]".to_string(),
+        };
+        Line::from(Span::styled(
+            format!("           ╭─ {}", quote),
+            Style::default()
+                .fg(Color::DarkGray)
+                .add_modifier(Modifier::ITALIC),
+        ))
+    }
+
+    fn render_rooms(&self, frame: &mut Frame, area: &Rect, state: &UiState) {
+        let items: Vec<ListItem> = state
+            .rooms
+            .iter()
+            .map(|(room, unread)| {
+                let label = conversation_label(room);
+                let (text, style) = if *room == state.current_room {
+                    (
+                        label,
+                        Style::default()
+                            .fg(Color::Black)
+                            .bg(Color::Cyan)
+                            .add_modifier(Modifier::BOLD),
+                    )
+                } else if *unread > 0 {
+                    (
+                        format!("{} ({})", label, unread),
+                        Style::default()
+                            .fg(Color::Yellow)
+                            .add_modifier(Modifier::BOLD),
+                    )
+                } else {
+                    (label, Style::default().fg(Color::Gray))
+                };
+                ListItem::new(Line::from(Span::styled(text, style)))
+            })
+            .collect();
+
+        let rooms_widget = List::new(items).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("Комнаты")
+                .border_style(Style::default().fg(Color::Cyan)),
+        );
+
+        frame.render_widget(rooms_widget, *area);
+    }
+
+    fn render_participants(&self, frame: &mut Frame, area: &Rect, state: &UiState) {
+        let me = std::iter::once((&state.username, state.away, state.active_at, true));
+        let others = state
+            .participants
+            .iter()
+            .map(|(name, participant)| (name, participant.away, participant.active_at, false));
+        let items: Vec<ListItem> = me
+            .chain(others)
+            .map(|(name, away, active_at, own)| {
+                let (marker, color) = if away {
+                    ("◐", Color::Yellow)
+                } else {
+                    ("●", Color::Green)
+                };
+                let mut spans = vec![
+                    Span::styled(format!("{} ", marker), Style::default().fg(color)),
+                    Span::styled(
+                        name.clone(),
+                        if own {
+                            Style::default().add_modifier(Modifier::BOLD)
+                        } else {
+                            Style::default()
+                        },
+                    ),
+                ];
+                if let Some(idle) = format_idle(active_at.elapsed()) {
+                    spans.push(Span::styled(
+                        format!(" {}", idle),
+                        Style::default().fg(Color::DarkGray),
+                    ));
+                }
+                ListItem::new(Line::from(spans))
+            })
+            .collect();
 
-        ListItem::new(Line::from(vec![
-            Span::styled(timestamp_str, Style::default().fg(Color::DarkGray)),
-            Span::styled(sender_str, sender_style),
-            Span::styled(msg.content.clone(), content_style),
-        ]))
+        let title = format!("В сети ({})", state.participants.len() + 1);
+        let participants_widget = List::new(items).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(title)
+                .border_style(Style::default().fg(Color::Cyan)),
+        );
+
+        frame.render_widget(participants_widget, *area);
+    }
+
+    fn render_discovered(&self, frame: &mut Frame, area: &Rect, state: &UiState) {
+        let items: Vec<ListItem> = state
+            .discovered
+            .iter()
+            .enumerate()
+            .map(|(i, peer)| {
+                let style = if i == state.selected_peer {
+                    Style::default()
+                        .fg(Color::Black)
+                        .bg(Color::Cyan)
+                        .add_modifier(Modifier::BOLD)
+                } else {
+                    Style::default().fg(Color::Gray)
+                };
+                ListItem::new(Line::from(Span::styled(
+                    format!("{} {} [{}]", peer.username, peer.addr, peer.fingerprint),
+                    style,
+                )))
+            })
+            .collect();
+
+        let peers_widget = List::new(items).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("В сети (Ctrl+N - выбор, Ctrl+O - подключиться)")
+                .border_style(Style::default().fg(Color::Cyan)),
+        );
+
+        frame.render_widget(peers_widget, *area);
     }
 
     fn render_input(&self, frame: &mut Frame, area: &Rect, state: &UiState) {
+        // строка "печатает…" над полем ввода, только пока кто-то набирает
+        let typing = state.typing_users();
+        let area = if typing.is_empty() {
+            *area
+        } else {
+            let rows = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Length(1), Constraint::Min(0)])
+                .split(*area);
+            let typing_widget = Paragraph::new(format_typing(&typing)).style(
+                Style::default()
+                    .fg(Color::DarkGray)
+                    .add_modifier(Modifier::ITALIC),
+            );
+            frame.render_widget(typing_widget, rows[0]);
+            rows[1]
+        };
+
+        let (title, color) = if matches!(state.input_mode, InputMode::Normal) {
+            (
+                "Выбор сообщения (↑↓ - выбор, Enter - ответить, t - ветка, e/+ - реакция, ESC - назад)"
+                    .to_string(),
+                Color::Cyan,
+            )
+        } else if state.editing.is_some() {
+            (
+                "Правка сообщения (Enter - сохранить, ESC - отмена)".to_string(),
+                Color::Yellow,
+            )
+        } else if let Some(target) = state
+            .replying
+            .as_ref()
+            .and_then(|id| state.messages.iter().find(|message| message.id == *id))
+        {
+            (
+                format!("Ответ {} (ESC - отмена)", target.sender),
+                Color::Yellow,
+            )
+        } else {
+            (
+                "Введите сообщение (ESC - выход, ↑ - правка, PgUp/PgDn - прокрутка)".to_string(),
+                Color::Green,
+            )
+        };
         let input_widget = Paragraph::new(state.get_input())
             .block(
                 Block::default()
                     .borders(Borders::ALL)
-                    .title("Введите сообщение (ESC - выход, ↑↓ - прокрутка)")
-                    .border_style(Style::default().fg(Color::Green)),
+                    .title(title)
+                    .border_style(Style::default().fg(color)),
             )
             .style(Style::default().fg(Color::White));
 
-        frame.render_widget(input_widget, *area);
+        frame.render_widget(input_widget, area);
     }
 
     fn render_status_bar(&self, frame: &mut Frame, area: &Rect, state: &UiState) {
-        let status_text = format!(
-            "Пользователь: {} | Сообщений: {} | Ctrl+C - выход",
+        let mut status_text = format!(
+            "Пользователь: {} [{}] | Сообщений: {} | Ctrl+S - выбор | Ctrl+P - участники | Ctrl+C - выход",
             state.username,
+            state.identity.fingerprint(),
             state.messages.len()
         );
+        let pending = state.pending_count();
+        if pending > 0 {
+            status_text.push_str(&format!(" | В очереди: {}", pending));
+        }
+        for (addr, connection) in &state.peer_states {
+            status_text.push_str(&format!(" | {}: {}", addr, connection));
+        }
+        for progress in state.transfers.values() {
+            let arrow = match progress.direction {
+                transfer::Direction::Upload => "↑",
+                transfer::Direction::Download => "↓",
+            };
+            status_text.push_str(&format!(
+                " | {} {} {}%",
+                arrow,
+                progress.name,
+                progress.percent()
+            ));
+        }
 
         let status_widget = Paragraph::new(status_text)
             .style(Style::default().bg(Color::DarkGray).fg(Color::White))
@@ -119,11 +497,42 @@ impl UiRenderer {
         frame.render_widget(status_widget, *area);
     }
 
-    fn format_timestamp(&self, timestamp: &str) -> String {
-        if let Ok(dt) = chrono::DateTime::parse_from_rfc2822(timestamp) {
-            dt.format("%H:%M:%S").to_string()
-        } else {
-            "??:??:??".to_string()
+    fn format_timestamp(&self, timestamp: &DateTime<Utc>) -> String {
+        timestamp
+            .with_timezone(&chrono::Local)
+            .format("%H:%M:%S")
+            .to_string()
+    }
+}
+
+// начало текста для цитаты, не длиннее QUOTE_WIDTH символов
+fn preview(text: &str) -> String {
+    let line = text.lines().next().unwrap_or_default();
+    if line.chars().count() > QUOTE_WIDTH || line.len() < text.len() {
+        let short: String = line.chars().take(QUOTE_WIDTH).collect();
+        format!("{}…", short)
+    } else {
+        line.to_string()
+    }
+}
+
+// время простоя: минуту и меньше не показываем
+fn format_idle(idle: Duration) -> Option<String> {
+    let minutes = idle.as_secs() / 60;
+    match minutes {
+        0 => None,
+        1..60 => Some(format!("{}м", minutes)),
+        _ => Some(format!("{}ч{}м", minutes / 60, minutes % 60)),
+    }
+}
+
+fn format_typing(users: &[&str]) -> String {
+    match users {
+        [user] => format!("{} печатает…", user),
+        [first, second] => format!("{} и {} печатают…", first, second),
+        [first, second, rest @ ..] => {
+            format!("{}, {} и ещё {} печатают…", first, second, rest.len())
         }
+        [] => String::new(),
     }
 }
@@ -1,8 +1,33 @@
-use crate::models::message::Message;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::identity::known_peers::{self, KnownPeers, PeerTrust};
+use crate::identity::{self, Identity, SignatureStatus};
+use crate::models::clock::{Hlc, HybridClock};
+use crate::models::message::{DEFAULT_ROOM, DELETED, DeliveryState, EDITED, Message, MessageKind};
+use crate::models::reaction;
+use crate::network::NetEvent;
+use crate::network::connection::PeerStates;
+use crate::network::discovery::DiscoveredPeer;
+use crate::network::protocol::{Frame, PresenceStatus};
+use crate::network::transfer::{Direction, TransferProgress, TransferState};
+use crate::storage::history::SharedHistory;
+
+// сколько сообщений подгружаем из истории за раз
+const HISTORY_PAGE: usize = 100;
+const MAX_ROOM_NAME: usize = 32;
+const MAX_NAME: usize = 32;
+// длиннее набрать нельзя: сообщение с подписью и обёрткой должно спокойно влезать в кадр
+const MAX_INPUT: usize = 4096;
+// о наборе текста сообщаем не чаще этого, а чужой индикатор гаснет через TYPING_TIMEOUT
+const TYPING_THROTTLE: Duration = Duration::from_secs(3);
+const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
 
 #[derive(Debug, Clone)]
 pub enum InputMode {
+    // выбор сообщения в ленте: ответить на него или открыть его ветку
     Normal,
+    // набор текста в строке ввода
     Editing,
 }
 
@@ -12,33 +37,196 @@ pub enum AppState {
     Quitting,
 }
 
+// участник в боковой панели; соединений с ним может быть несколько — напрямую и через хаб
+#[derive(Debug, Clone)]
+pub struct Participant {
+    connections: usize,
+    pub away: bool,
+    // последнее сообщение или смена статуса — от неё считаем время простоя
+    pub active_at: Instant,
+}
+
 pub struct UiState {
+    // лента текущей комнаты
     pub messages: Vec<Message>,
+    pub current_room: String,
+    // комнаты, в которых состоим, и число непрочитанных в каждой
+    pub rooms: BTreeMap<String, usize>,
     pub input: String,
     pub input_mode: InputMode,
+    // id своего сообщения, которое сейчас правим в строке ввода
+    pub editing: Option<String>,
+    // выбранное сообщение в режиме выбора, сообщение, на которое отвечаем, и корень открытой ветки
+    pub selected: Option<String>,
+    pub replying: Option<String>,
+    pub thread: Option<String>,
+    // реакции на сообщения открытой переписки: id -> эмодзи -> кто поставил
+    pub reactions: HashMap<String, BTreeMap<String, BTreeSet<String>>>,
+    // сообщение, для которого набираем /react после выбора
+    reacting: Option<String>,
     pub app_state: AppState,
     pub username: String,
-    pub token: String,
+    pub identity: Identity,
+    pub known_peers: KnownPeers,
+    // сообщения без подписи или от ключа, не совпавшего с закреплённым
+    pub untrusted: HashSet<String>,
     pub scroll_offset: usize,
     pub max_messages: usize,
+    // узлы, найденные в локальной сети, и выбранный из них
+    pub discovered: Vec<DiscoveredPeer>,
+    pub selected_peer: usize,
+    // состояние исходящих соединений для строки статуса
+    pub peer_states: PeerStates,
+    // статус доставки своих сообщений по id
+    pub delivery: HashMap<String, DeliveryState>,
+    pub read_receipts: bool,
+    // кто сейчас в сети, кроме нас
+    pub participants: BTreeMap<String, Participant>,
+    pub show_participants: bool,
+    pub away: bool,
+    pub active_at: Instant,
+    // кто что набирает: имя -> (переписка, когда сообщил); в ленту и историю не попадает
+    typing: BTreeMap<String, (String, Instant)>,
+    typing_sent: Option<Instant>,
+    // идущие передачи файлов по (id, собеседник)
+    pub transfers: BTreeMap<(String, String), TransferProgress>,
+    // полученные сообщения, о прочтении которых ещё не сообщили
+    unread: HashSet<String>,
+    // квитанции, статусы, правки и удаления, которые цикл уи отправит в сеть
+    outgoing: Vec<Frame>,
+    history: SharedHistory,
+    // в истории не осталось сообщений старше загруженных
+    history_exhausted: bool,
+    // высота ленты с последней отрисовки
+    visible_height: usize,
+    clock: HybridClock,
 }
 
 impl UiState {
-    pub fn new(username: String, token: String) -> Self {
-        Self {
+    pub fn new(
+        username: String,
+        identity: Identity,
+        known_peers: KnownPeers,
+        read_receipts: bool,
+        history: SharedHistory,
+    ) -> Self {
+        let mut rooms = BTreeMap::from([(DEFAULT_ROOM.to_string(), 0)]);
+        let joined = history.lock().unwrap().rooms();
+        for room in joined.iter().flatten() {
+            rooms.insert(room.clone(), 0);
+        }
+
+        let mut state = Self {
             messages: Vec::new(),
+            current_room: DEFAULT_ROOM.to_string(),
+            rooms,
             input: String::new(),
-            input_mode: InputMode::Normal,
+            input_mode: InputMode::Editing,
+            editing: None,
+            selected: None,
+            replying: None,
+            thread: None,
+            reactions: HashMap::new(),
+            reacting: None,
             app_state: AppState::Running,
             username,
-            token,
+            identity,
+            known_peers,
+            untrusted: HashSet::new(),
             scroll_offset: 0,
             max_messages: 1000,
+            discovered: Vec::new(),
+            selected_peer: 0,
+            peer_states: PeerStates::new(),
+            delivery: HashMap::new(),
+            read_receipts,
+            participants: BTreeMap::new(),
+            show_participants: true,
+            away: false,
+            active_at: Instant::now(),
+            typing: BTreeMap::new(),
+            typing_sent: None,
+            transfers: BTreeMap::new(),
+            unread: HashSet::new(),
+            outgoing: Vec::new(),
+            history,
+            history_exhausted: false,
+            visible_height: 0,
+            clock: HybridClock::default(),
+        };
+        state.load_older();
+        state.load_reactions();
+        // после перезапуска часы не должны уйти назад относительно уже отправленного
+        for message in &state.messages {
+            if let Some(clock) = message.clock {
+                state.clock.observe(clock);
+            }
+        }
+        state
+    }
+
+    // подгружаем страницу сообщений старше самого раннего из показанных
+    fn load_older(&mut self) {
+        if self.history_exhausted {
+            return;
+        }
+        let page = {
+            let history = self.history.lock().unwrap();
+            let cursor = self
+                .messages
+                .iter()
+                .find(|message| history.contains(&message.id))
+                .map(|message| message.id.as_str());
+            history.load_page(&self.current_room, cursor, HISTORY_PAGE)
+        };
+        let page = match page {
+            Ok(page) => page,
+            Err(e) => {
+                self.history_exhausted = true;
+                self.add_notice(format!("Ошибка чтения истории: {}", e));
+                return;
+            }
+        };
+        if page.len() < HISTORY_PAGE {
+            self.history_exhausted = true;
+        }
+
+        let loaded = page.len();
+        let older: Vec<Message> = page
+            .into_iter()
+            .map(|stored| {
+                if stored.untrusted {
+                    self.untrusted.insert(stored.message.id.clone());
+                }
+                stored.message
+            })
+            .collect();
+        self.messages.splice(0..0, older);
+        self.scroll_offset += loaded;
+        if self.scroll_offset >= self.messages.len() {
+            self.scroll_to_bottom();
+        }
+    }
+
+    fn save_message(&mut self, message: &Message, untrusted: bool) {
+        let saved = self
+            .history
+            .lock()
+            .unwrap()
+            .save(&message.room, message, untrusted);
+        if let Err(e) = saved {
+            self.add_notice(format!("Ошибка записи истории: {}", e));
         }
     }
 
     pub fn add_message(&mut self, message: Message) {
-        self.messages.push(message);
+        // сообщение встаёт на своё место по HLC, а не в конец: так расхождение часов
+        // между машинами и досланное при синхронизации не перемешивают переписку
+        let key = message.order_key();
+        let position = self
+            .messages
+            .partition_point(|existing| existing.order_key() <= key);
+        self.messages.insert(position, message);
 
         if self.messages.len() > self.max_messages {
             self.messages.remove(0);
@@ -47,16 +235,775 @@ impl UiState {
         self.scroll_to_bottom();
     }
 
+    pub fn handle_net_event(&mut self, event: NetEvent) {
+        match event {
+            NetEvent::Frame(Frame::Chat(message)) => self.receive_message(message, true),
+            NetEvent::Backfill(message) => self.receive_message(*message, false),
+            NetEvent::Frame(Frame::Direct(message)) => self.receive_direct(message),
+            NetEvent::Frame(Frame::Edit(message)) => self.receive_edit(message),
+            NetEvent::Frame(Frame::Delete {
+                id,
+                at,
+                public_key,
+                signature,
+            }) => self.receive_delete(id, at, public_key, signature),
+            NetEvent::Frame(Frame::Error { message, .. }) => self.add_notice(message),
+            NetEvent::Frame(Frame::Presence { username, status }) => {
+                self.update_presence(username, status)
+            }
+            NetEvent::Frame(Frame::Typing { username, room, to }) => {
+                self.update_typing(username, room, to)
+            }
+            NetEvent::Frame(Frame::Reaction {
+                id,
+                emoji,
+                on,
+                at,
+                public_key,
+                signature,
+                ..
+            }) => self.receive_reaction(id, emoji, on, at, public_key, signature),
+            NetEvent::Frame(Frame::Ack { id, .. }) => {
+                self.update_delivery(id, DeliveryState::Delivered)
+            }
+            NetEvent::Frame(Frame::Read { id, .. }) => {
+                self.update_delivery(id, DeliveryState::Read)
+            }
+            NetEvent::Frame(_) => {}
+            NetEvent::Notice(text) => self.add_notice(text),
+            NetEvent::Discovered(peers) => self.set_discovered(peers),
+            NetEvent::Delivery { id, state } => self.update_delivery(id, state),
+            NetEvent::Transfer(progress) => self.update_transfer(progress),
+        }
+    }
+
+    fn update_presence(&mut self, username: String, status: PresenceStatus) {
+        if username == self.username {
+            return;
+        }
+        let notice = match status {
+            PresenceStatus::Join => {
+                let participant =
+                    self.participants
+                        .entry(username.clone())
+                        .or_insert(Participant {
+                            connections: 0,
+                            away: false,
+                            active_at: Instant::now(),
+                        });
+                participant.connections += 1;
+                (participant.connections == 1).then(|| format!("{} в сети", username))
+            }
+            PresenceStatus::Leave => match self.participants.get_mut(&username) {
+                Some(participant) if participant.connections > 1 => {
+                    participant.connections -= 1;
+                    None
+                }
+                Some(_) => {
+                    self.participants.remove(&username);
+                    Some(format!("{} вышел из сети", username))
+                }
+                None => None,
+            },
+            PresenceStatus::Away | PresenceStatus::Back => {
+                if let Some(participant) = self.participants.get_mut(&username) {
+                    participant.away = status == PresenceStatus::Away;
+                    participant.active_at = Instant::now();
+                }
+                None
+            }
+        };
+        if let Some(notice) = notice {
+            self.add_notice(notice);
+        }
+    }
+
+    fn update_typing(&mut self, username: String, room: String, to: Option<String>) {
+        if username == self.username {
+            return;
+        }
+        let conversation = match to {
+            Some(to) if to == self.username => direct_conversation(&username),
+            Some(_) => return,
+            None => room,
+        };
+        self.typing
+            .retain(|_, (_, at)| at.elapsed() < TYPING_TIMEOUT);
+        self.typing.insert(username, (conversation, Instant::now()));
+    }
+
+    // кто набирает текст в открытой переписке прямо сейчас
+    pub fn typing_users(&self) -> Vec<&str> {
+        self.typing
+            .iter()
+            .filter(|(_, (room, at))| *room == self.current_room && at.elapsed() < TYPING_TIMEOUT)
+            .map(|(username, _)| username.as_str())
+            .collect()
+    }
+
+    // о наборе сообщаем не чаще раза в TYPING_THROTTLE; команды не в счёт
+    fn note_typing(&mut self) {
+        if self.input.is_empty() || self.input.starts_with('/') {
+            return;
+        }
+        if self
+            .typing_sent
+            .is_some_and(|sent| sent.elapsed() < TYPING_THROTTLE)
+        {
+            return;
+        }
+        self.typing_sent = Some(Instant::now());
+        let (room, to) = match self.direct_peer() {
+            Some(peer) => (String::new(), Some(peer.to_string())),
+            None => (self.current_room.clone(), None),
+        };
+        self.outgoing.push(Frame::Typing {
+            username: self.username.clone(),
+            room,
+            to,
+        });
+    }
+
+    fn touch_participant(&mut self, username: &str) {
+        self.typing.remove(username);
+        if let Some(participant) = self.participants.get_mut(username) {
+            participant.active_at = Instant::now();
+        }
+    }
+
+    // статус уходит соседям вместе с квитанциями; false — он и так такой
+    pub fn set_away(&mut self, away: bool) -> bool {
+        if self.away == away {
+            return false;
+        }
+        self.away = away;
+        self.active_at = Instant::now();
+        let status = if away {
+            PresenceStatus::Away
+        } else {
+            PresenceStatus::Back
+        };
+        self.outgoing.push(Frame::presence(&self.username, status));
+        true
+    }
+
+    pub fn toggle_participants(&mut self) {
+        self.show_participants = !self.show_participants;
+    }
+
+    // прогресс держим в строке статуса, а начало и конец передачи пишем в ленту
+    fn update_transfer(&mut self, progress: TransferProgress) {
+        let key = (progress.id.clone(), progress.peer.clone());
+        let name = &progress.name;
+        let notice = match (&progress.state, progress.direction) {
+            (TransferState::Running, _) => {
+                self.transfers.insert(key, progress);
+                return;
+            }
+            (TransferState::Offered, Direction::Download) => format!(
+                "{} предлагает файл {} ({}), принять: /accept {}",
+                progress.peer,
+                name,
+                format_size(progress.total),
+                progress.id
+            ),
+            (TransferState::Offered, Direction::Upload) => format!(
+                "Файл {} ({}) предложен собеседникам",
+                name,
+                format_size(progress.total)
+            ),
+            (TransferState::Done(Some(path)), _) => {
+                format!("Файл {} сохранён: {}", name, path.display())
+            }
+            (TransferState::Done(None), _) => {
+                format!("Файл {} отправлен {}", name, progress.peer)
+            }
+            (TransferState::Interrupted, _) => format!(
+                "Передача файла {} прервалась, продолжим после переподключения",
+                name
+            ),
+            (TransferState::Failed(reason), Direction::Download) => {
+                format!("Не удалось получить файл {}: {}", name, reason)
+            }
+            (TransferState::Failed(reason), Direction::Upload) => {
+                format!("Не удалось отправить файл {}: {}", name, reason)
+            }
+        };
+        self.transfers.remove(&key);
+        self.add_notice(notice);
+    }
+
+    pub fn add_own_message(&mut self, message: Message) {
+        self.delivery
+            .insert(message.id.clone(), DeliveryState::Pending);
+        self.save_message(&message, false);
+        self.add_message(message);
+    }
+
+    // статус только растёт; чужие id (пересланные нами сообщения) не отслеживаем
+    fn update_delivery(&mut self, id: String, state: DeliveryState) {
+        if let Some(current) = self.delivery.get_mut(&id)
+            && *current < state
+        {
+            *current = state;
+        }
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.delivery
+            .values()
+            .filter(|state| **state == DeliveryState::Pending)
+            .count()
+    }
+
+    fn set_discovered(&mut self, peers: Vec<DiscoveredPeer>) {
+        // выбор остаётся на том же узле, если он никуда не пропал
+        let selected = self
+            .selected_discovered()
+            .map(|peer| peer.fingerprint.clone());
+        self.discovered = peers;
+        self.selected_peer = selected
+            .and_then(|fingerprint| {
+                self.discovered
+                    .iter()
+                    .position(|peer| peer.fingerprint == fingerprint)
+            })
+            .unwrap_or(0);
+    }
+
+    pub fn selected_discovered(&self) -> Option<&DiscoveredPeer> {
+        self.discovered.get(self.selected_peer)
+    }
+
+    pub fn select_next_peer(&mut self) {
+        if !self.discovered.is_empty() {
+            self.selected_peer = (self.selected_peer + 1) % self.discovered.len();
+        }
+    }
+
+    // общие проверки входящего: повтор, подделка служебной строки, подпись и ключ автора;
+    // false — сообщение отброшено
+    fn verify_incoming(&mut self, message: &Message) -> bool {
+        // уже есть в истории — например, пир дослал очередь после нашего перезапуска
+        if self.history.lock().unwrap().contains(&message.id) {
+            return false;
+        }
+        // служебные строки рисует только сам чат — чужие выдавали бы себя за них
+        if message.kind == MessageKind::System {
+            self.add_notice(format!(
+                "Отброшено служебное сообщение от {}",
+                message.sender
+            ));
+            return false;
+        }
+
+        match identity::verify_message(message) {
+            SignatureStatus::Invalid => {
+                self.add_notice(format!(
+                    "Отброшено сообщение от {} с неверной подписью",
+                    message.sender
+                ));
+                return false;
+            }
+            SignatureStatus::Unsigned => {
+                self.untrusted.insert(message.id.clone());
+            }
+            SignatureStatus::Valid => {
+                if !known_peers::valid_name(&message.sender) {
+                    self.add_notice(format!(
+                        "Отброшено сообщение с недопустимым именем {:?}",
+                        message.sender
+                    ));
+                    return false;
+                }
+                let public_key = message.public_key.clone().unwrap_or_default();
+                match self.known_peers.check(&message.sender, &public_key) {
+                    Ok(PeerTrust::Known) => {}
+                    Ok(PeerTrust::New) => self.add_notice(format!(
+                        "Новый собеседник {}, отпечаток {}",
+                        message.sender,
+                        identity::fingerprint(&public_key)
+                    )),
+                    Ok(PeerTrust::KeyChanged { pinned }) => {
+                        self.untrusted.insert(message.id.clone());
+                        self.add_notice(format!(
+                            "!!! ВНИМАНИЕ: ключ пользователя {} изменился ({} -> {}). Возможна подмена! !!!",
+                            message.sender,
+                            identity::fingerprint(&pinned),
+                            identity::fingerprint(&public_key)
+                        ));
+                    }
+                    Err(e) => self.add_notice(format!("Ошибка записи known_peers: {}", e)),
+                }
+            }
+        }
+        true
+    }
+
+    // live = false — досланное синхронизацией: его давно отправили, квитанции никому не нужны
+    fn receive_message(&mut self, message: Message, live: bool) {
+        // личное сообщение под видом обычного попало бы в чужую переписку
+        if message.to.is_some() || message.room.starts_with('@') {
+            return;
+        }
+        if !self.verify_incoming(&message) {
+            return;
+        }
+
+        if let Some(clock) = message.clock {
+            self.clock.observe(clock);
+        }
+        self.touch_participant(&message.sender);
+        let untrusted = self.untrusted.contains(&message.id);
+        self.save_message(&message, untrusted);
+        if live && !self.is_own_message(&message) {
+            self.outgoing.push(Frame::Ack {
+                id: message.id.clone(),
+                username: self.username.clone(),
+            });
+            if self.read_receipts {
+                self.unread.insert(message.id.clone());
+            }
+        }
+        // сообщения чужих комнат только сохраняем: увидим их, когда войдём
+        if message.room == self.current_room {
+            if self.fits_feed(&message) {
+                self.add_message(message);
+            }
+        } else if let Some(unread) = self.rooms.get_mut(&message.room) {
+            *unread += 1;
+        }
+    }
+
+    // личное сообщение: расшифровываем и кладём в переписку "@отправитель";
+    // квитанций по ним не шлём — они разошлись бы всем
+    fn receive_direct(&mut self, mut message: Message) {
+        if message.to.as_deref() != Some(self.username.as_str()) {
+            return;
+        }
+        if !self.verify_incoming(&message) {
+            return;
+        }
+        if let Err(e) = self.identity.open_direct(&mut message) {
+            self.add_notice(format!(
+                "Не удалось прочитать личное сообщение от {}: {}",
+                message.sender, e
+            ));
+            return;
+        }
+
+        if let Some(clock) = message.clock {
+            self.clock.observe(clock);
+        }
+        self.touch_participant(&message.sender);
+        message.room = direct_conversation(&message.sender);
+        let untrusted = self.untrusted.contains(&message.id);
+        self.save_message(&message, untrusted);
+        if message.room == self.current_room {
+            if self.fits_feed(&message) {
+                self.add_message(message);
+            }
+        } else {
+            self.add_conversation(&message.room);
+            if let Some(unread) = self.rooms.get_mut(&message.room) {
+                *unread += 1;
+            }
+        }
+    }
+
+    // править может только автор: подпись тем же ключом, что и у оригинала, и та же комната
+    fn receive_edit(&mut self, message: Message) {
+        if identity::verify_message(&message) != SignatureStatus::Valid {
+            self.add_notice(format!(
+                "Отклонена правка от {}: нет подписи или она неверна",
+                message.sender
+            ));
+            return;
+        }
+        // оригинала у нас нет — править нечего, досинхронизация принесёт свежую версию
+        let Some(original) = self.find_message(&message.id) else {
+            return;
+        };
+        let same_author = original.sender == message.sender
+            && original.public_key.is_some()
+            && original.public_key == message.public_key
+            && original.room == message.room
+            && message.to.is_none();
+        if !same_author {
+            self.add_notice(format!(
+                "Отклонена правка сообщения {} от {}: автор не совпадает",
+                original.sender, message.sender
+            ));
+            return;
+        }
+        // меняется только текст: подменённые вид, часы или время выдали бы правку за
+        // служебную строку и сдвинули бы её в ленте
+        let mut metadata = message.metadata.clone();
+        metadata.remove(EDITED);
+        let mut original_metadata = original.metadata.clone();
+        original_metadata.remove(EDITED);
+        let same_shape = original.kind == message.kind
+            && original.clock == message.clock
+            && original.timestamp.timestamp() == message.timestamp.timestamp()
+            && original.reply_to == message.reply_to
+            && original_metadata == metadata;
+        if !same_shape {
+            self.add_notice(format!(
+                "Отклонена правка сообщения {}: меняет не только текст",
+                original.sender
+            ));
+            return;
+        }
+        // удалённое не воскрешаем, а правки, пришедшие не по порядку, не откатывают текст назад
+        if original.is_deleted() || message.metadata.get(EDITED) <= original.metadata.get(EDITED) {
+            return;
+        }
+        self.touch_participant(&message.sender);
+        self.update_message(message);
+    }
+
+    fn receive_delete(&mut self, id: String, at: i64, public_key: String, signature: String) {
+        if !identity::verify_deletion(&id, at, &public_key, &signature) {
+            self.add_notice("Отклонено удаление с неверной подписью".to_string());
+            return;
+        }
+        let Some(original) = self.find_message(&id) else {
+            return;
+        };
+        if original.public_key.as_deref() != Some(public_key.as_str()) {
+            self.add_notice(format!(
+                "Отклонено удаление сообщения {}: автор не совпадает",
+                original.sender
+            ));
+            return;
+        }
+        if !original.is_deleted() {
+            self.update_message(tombstone(original, at, signature));
+        }
+    }
+
+    // в открытой ветке показываем только ответы на то, что в ней уже есть
+    fn fits_feed(&self, message: &Message) -> bool {
+        let Some(root) = &self.thread else {
+            return true;
+        };
+        message.id == *root
+            || message
+                .reply_to
+                .as_ref()
+                .is_some_and(|parent| self.messages.iter().any(|existing| existing.id == *parent))
+    }
+
+    // то, на что отвечает сообщение, — для цитаты над ним
+    pub fn reply_target(&self, message: &Message) -> Option<Message> {
+        message
+            .reply_to
+            .as_deref()
+            .and_then(|id| self.find_message(id))
+    }
+
+    fn load_reactions(&mut self) {
+        self.reactions.clear();
+        let reactions = self.history.lock().unwrap().reactions(&self.current_room);
+        match reactions {
+            Ok(reactions) => {
+                for (id, public_key, emoji) in reactions {
+                    self.apply_reaction(id, public_key, emoji, true);
+                }
+            }
+            Err(e) => self.add_notice(format!("Ошибка чтения истории: {}", e)),
+        }
+    }
+
+    fn apply_reaction(&mut self, id: String, public_key: String, emoji: String, on: bool) {
+        let emojis = self.reactions.entry(id.clone()).or_default();
+        if on {
+            emojis.entry(emoji).or_default().insert(public_key);
+        } else if let Some(users) = emojis.get_mut(&emoji) {
+            users.remove(&public_key);
+            if users.is_empty() {
+                emojis.remove(&emoji);
+            }
+        }
+        if emojis.is_empty() {
+            self.reactions.remove(&id);
+        }
+    }
+
+    // реакцию сохраняем, даже если само сообщение ещё не дошло; на экран — только для открытой переписки
+    fn receive_reaction(
+        &mut self,
+        id: String,
+        emoji: String,
+        on: bool,
+        at: i64,
+        public_key: String,
+        signature: String,
+    ) {
+        if !reaction::is_emoji(&emoji)
+            || !identity::verify_reaction(&id, &emoji, on, at, &public_key, &signature)
+        {
+            return;
+        }
+        // время из будущего не должно закреплять реакцию навсегда: не дальше наших часов
+        self.clock.observe(Hlc {
+            wall: at,
+            logical: 0,
+        });
+        let at = at.min(self.clock.latest().wall);
+        let saved = self
+            .history
+            .lock()
+            .unwrap()
+            .save_reaction(&id, &public_key, &emoji, on, at);
+        match saved {
+            Ok(true) => {
+                let visible = self
+                    .find_message(&id)
+                    .is_some_and(|message| message.room == self.current_room);
+                if visible {
+                    self.apply_reaction(id, public_key, emoji, on);
+                }
+            }
+            Ok(false) => {}
+            Err(e) => self.add_notice(format!("Ошибка записи истории: {}", e)),
+        }
+    }
+
+    // повторная та же реакция снимает её; без id — на выбранное через e или последнее в ленте
+    pub fn react(&mut self, emoji: String, id: Option<String>) -> Result<(), String> {
+        if self.direct_peer().is_some() {
+            return Err("в личной переписке реакции не поддерживаются".to_string());
+        }
+        let id = id
+            .or_else(|| self.reacting.take())
+            .or_else(|| {
+                self.messages
+                    .iter()
+                    .rev()
+                    .find(|message| selectable(message) && !message.is_deleted())
+                    .map(|message| message.id.clone())
+            })
+            .ok_or_else(|| "нет сообщения для реакции".to_string())?;
+
+        let own_key = self.identity.public_key();
+        let on = !self
+            .reactions
+            .get(&id)
+            .and_then(|emojis| emojis.get(&emoji))
+            .is_some_and(|users| users.contains(&own_key));
+        // по HLC и строго позже прошлой версии, даже если часы стоят на месте
+        let at = {
+            let history = self.history.lock().unwrap();
+            let saved = history
+                .reaction_at(&id, &own_key, &emoji)
+                .and_then(|previous| {
+                    let at = self
+                        .clock
+                        .tick()
+                        .wall
+                        .max(previous.map_or(i64::MIN, |at| at + 1));
+                    history
+                        .save_reaction(&id, &own_key, &emoji, on, at)
+                        .map(|_| at)
+                });
+            saved.map_err(|e| format!("ошибка записи истории: {}", e))?
+        };
+        let (public_key, signature) = self.identity.sign_reaction(&id, &emoji, on, at);
+        self.apply_reaction(id.clone(), own_key, emoji.clone(), on);
+        self.outgoing.push(Frame::Reaction {
+            id,
+            username: self.username.clone(),
+            emoji,
+            on,
+            at,
+            public_key,
+            signature,
+        });
+        Ok(())
+    }
+
+    // e в режиме выбора: набираем /react для выбранного сообщения
+    pub fn react_to_selected(&mut self) {
+        self.reacting = self.selected.clone();
+        self.stop_selection();
+        self.input = "/react ".to_string();
+    }
+
+    // сначала ищем в ленте, затем в истории — сообщение могло уйти из показанного окна
+    fn find_message(&self, id: &str) -> Option<Message> {
+        if let Some(message) = self.messages.iter().find(|message| message.id == id) {
+            return Some(message.clone());
+        }
+        match self.history.lock().unwrap().get(id) {
+            Ok(stored) => stored.map(|stored| stored.message),
+            Err(_) => None,
+        }
+    }
+
+    // новая версия встаёт на место старой и в истории, и в ленте
+    fn update_message(&mut self, message: Message) {
+        let replaced = self.history.lock().unwrap().replace(&message);
+        if let Err(e) = replaced {
+            self.add_notice(format!("Ошибка записи истории: {}", e));
+        }
+        if let Some(existing) = self
+            .messages
+            .iter_mut()
+            .find(|existing| existing.id == message.id)
+        {
+            *existing = message;
+        }
+    }
+
+    // комната или личная переписка попадает в список и переживает перезапуск
+    fn add_conversation(&mut self, room: &str) {
+        if self.rooms.contains_key(room) {
+            return;
+        }
+        let joined = self.history.lock().unwrap().join_room(room);
+        if let Err(e) = joined {
+            self.add_notice(format!("Ошибка записи истории: {}", e));
+        }
+        self.rooms.insert(room.to_string(), 0);
+    }
+
+    // false — написать этому собеседнику нельзя
+    pub fn open_direct(&mut self, user: &str) -> bool {
+        if user == self.username {
+            self.add_notice("Нельзя написать личное сообщение самому себе".to_string());
+            return false;
+        }
+        let room = direct_conversation(user);
+        self.add_conversation(&room);
+        if room != self.current_room {
+            self.switch_room(room);
+        }
+        true
+    }
+
+    // собеседник, если открыта личная переписка
+    pub fn direct_peer(&self) -> Option<&str> {
+        self.current_room.strip_prefix('@')
+    }
+
+    pub fn join_room(&mut self, name: &str) {
+        let Some(room) = room_name(name) else {
+            self.add_notice(format!(
+                "Некорректное имя комнаты: {} (латиница, цифры, - и _, до {} символов)",
+                name, MAX_ROOM_NAME
+            ));
+            return;
+        };
+        self.add_conversation(&room);
+        self.switch_room(room);
+    }
+
+    // без имени — выходим из текущей комнаты; из общей выйти нельзя
+    pub fn leave_room(&mut self, name: Option<&str>) {
+        let room = match name {
+            Some(name) => room_name(name).unwrap_or_else(|| name.to_string()),
+            None => self.current_room.clone(),
+        };
+        if room == DEFAULT_ROOM {
+            self.add_notice(format!("Из #{} выйти нельзя", DEFAULT_ROOM));
+            return;
+        }
+        if self.rooms.remove(&room).is_none() {
+            self.add_notice(format!("Вы не состоите в {}", conversation_label(&room)));
+            return;
+        }
+        let left = self.history.lock().unwrap().leave_room(&room);
+        if let Err(e) = left {
+            self.add_notice(format!("Ошибка записи истории: {}", e));
+        }
+        if room == self.current_room {
+            self.switch_room(DEFAULT_ROOM.to_string());
+        }
+        self.add_notice(format!("Вы вышли из {}", conversation_label(&room)));
+    }
+
+    pub fn list_rooms(&mut self) {
+        let rooms: Vec<String> = self
+            .rooms
+            .iter()
+            .map(|(room, unread)| match unread {
+                0 => conversation_label(room),
+                unread => format!("{} ({})", conversation_label(room), unread),
+            })
+            .collect();
+        self.add_notice(format!("Комнаты: {}", rooms.join(", ")));
+    }
+
+    // лента целиком перечитывается из истории выбранной комнаты
+    fn switch_room(&mut self, room: String) {
+        self.editing = None;
+        self.replying = None;
+        self.thread = None;
+        self.reacting = None;
+        self.stop_selection();
+        self.rooms.insert(room.clone(), 0);
+        self.current_room = room;
+        self.messages.clear();
+        self.scroll_offset = 0;
+        self.history_exhausted = false;
+        self.load_older();
+        self.load_reactions();
+        self.scroll_to_bottom();
+    }
+
+    pub fn set_visible_height(&mut self, height: usize) {
+        self.visible_height = height;
+    }
+
+    // сообщения, попавшие на экран, считаем прочитанными
+    pub fn mark_visible_read(&mut self) {
+        if self.unread.is_empty() {
+            return;
+        }
+        let visible: Vec<String> = self
+            .get_visible_messages(self.visible_height)
+            .iter()
+            .filter(|message| self.unread.contains(&message.id))
+            .map(|message| message.id.clone())
+            .collect();
+        for id in visible {
+            self.unread.remove(&id);
+            self.outgoing.push(Frame::Read {
+                id,
+                username: self.username.clone(),
+            });
+        }
+    }
+
+    pub fn take_outgoing(&mut self) -> Vec<Frame> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    pub fn is_own_message(&self, message: &Message) -> bool {
+        message.public_key.as_deref() == Some(self.identity.public_key().as_str())
+    }
+
+    // служебные уведомления показываем прямо в ленте
+    pub fn add_notice(&mut self, text: String) {
+        self.add_message(Message::system(text));
+    }
+
     pub fn clear_input(&mut self) {
         self.input.clear();
     }
 
     pub fn push_char(&mut self, c: char) {
+        if self.input.chars().count() >= MAX_INPUT {
+            return;
+        }
         self.input.push(c);
+        self.note_typing();
     }
 
     pub fn pop_char(&mut self) {
         self.input.pop();
+        self.note_typing();
     }
 
     pub fn is_input_empty(&self) -> bool {
@@ -67,16 +1014,326 @@ impl UiState {
         &self.input
     }
 
-    pub fn create_message(&self) -> Message {
-        Message::new(
-            self.username.clone(),
-            self.input.clone(),
-            chrono::Utc::now().to_rfc2822(),
-            self.token.clone(),
-        )
+    pub fn create_message(&mut self, text: String, kind: MessageKind) -> Message {
+        self.mark_active();
+        let mut message = Message::new(self.username.clone(), text, chrono::Utc::now());
+        message.kind = kind;
+        message.room = self.current_room.clone();
+        // в открытой ветке всё, что не ответ на конкретное, отвечает её корню
+        message.reply_to = self.replying.take().or_else(|| self.thread.clone());
+        message.clock = Some(self.clock.tick());
+        self.identity.sign_message(&mut message);
+        message
+    }
+
+    // в сеть уходит зашифрованное сообщение, себе в историю — открытый текст
+    pub fn create_direct(
+        &mut self,
+        to: &str,
+        text: String,
+        kind: MessageKind,
+    ) -> (Message, Message) {
+        self.mark_active();
+        let mut message = Message::new(self.username.clone(), text, chrono::Utc::now());
+        message.kind = kind;
+        message.to = Some(to.to_string());
+        message.reply_to = self.replying.take().or_else(|| self.thread.clone());
+        message.clock = Some(self.clock.tick());
+        let mut local = message.clone();
+
+        let sealed = match self.known_peers.get(to).map(str::to_string) {
+            Some(key) => self.identity.seal_direct(&mut message, &key),
+            None => false,
+        };
+        if !sealed {
+            self.add_notice(format!(
+                "Ключ {} ещё неизвестен, сообщение уйдёт без шифрования",
+                to
+            ));
+        }
+        self.identity.sign_message(&mut message);
+
+        local.room = direct_conversation(to);
+        local.metadata = message.metadata.clone();
+        local.public_key = message.public_key.clone();
+        local.signature = message.signature.clone();
+        (message, local)
+    }
+
+    // своё последнее сообщение в открытой комнате; личные переписки не правим —
+    // правка разошлась бы всем открытым текстом
+    fn last_own_message(&self, editable: bool) -> Option<&Message> {
+        if self.direct_peer().is_some() {
+            return None;
+        }
+        self.messages.iter().rev().find(|message| {
+            self.is_own_message(message)
+                && !message.is_deleted()
+                && (!editable || matches!(message.kind, MessageKind::Text | MessageKind::Action))
+        })
+    }
+
+    // ↑ на пустой строке: текст своего последнего сообщения уходит в строку ввода
+    pub fn start_edit(&mut self) -> bool {
+        let Some(message) = self.last_own_message(true).cloned() else {
+            return false;
+        };
+        self.input = message.content;
+        self.editing = Some(message.id);
+        true
+    }
+
+    pub fn cancel_edit(&mut self) -> bool {
+        if self.editing.take().is_none() {
+            return false;
+        }
+        self.clear_input();
+        true
+    }
+
+    // тот же id и место в ленте, новый текст, отметка о правке и свежая подпись
+    pub fn finish_edit(&mut self, text: String) {
+        let Some(id) = self.editing.take() else {
+            return;
+        };
+        let Some(mut message) = self.find_message(&id) else {
+            self.add_notice("Сообщение для правки не найдено".to_string());
+            return;
+        };
+        if message.content == text {
+            return;
+        }
+        self.mark_active();
+        message.content = text;
+        message.metadata.insert(EDITED.to_string(), now_stamp());
+        self.identity.sign_message(&mut message);
+        self.update_message(message.clone());
+        self.outgoing.push(Frame::Edit(message));
+    }
+
+    // без id — своё последнее сообщение; id можно указать начальными символами
+    pub fn delete_message(&mut self, id: Option<&str>) -> Result<(), String> {
+        if self.direct_peer().is_some() {
+            return Err("в личной переписке удаление не поддерживается".to_string());
+        }
+        let message = match id {
+            None => self.last_own_message(false).cloned(),
+            Some(prefix) => {
+                let mut found = self
+                    .messages
+                    .iter()
+                    .filter(|message| message.id.starts_with(prefix));
+                match (found.next(), found.next()) {
+                    (Some(message), None) => Some(message.clone()),
+                    (Some(_), Some(_)) => {
+                        return Err(format!("под {} подходит несколько сообщений", prefix));
+                    }
+                    _ => self.find_message(prefix),
+                }
+            }
+        };
+        let Some(message) = message else {
+            return Err("сообщение не найдено".to_string());
+        };
+        if !self.is_own_message(&message) {
+            return Err("удалять можно только свои сообщения".to_string());
+        }
+        if message.is_deleted() {
+            return Err("сообщение уже удалено".to_string());
+        }
+
+        let at = chrono::Utc::now().timestamp_millis();
+        let (public_key, signature) = self.identity.sign_deletion(&message.id, at);
+        self.outgoing.push(Frame::Delete {
+            id: message.id.clone(),
+            at,
+            public_key,
+            signature: signature.clone(),
+        });
+        if self.editing.as_deref() == Some(message.id.as_str()) {
+            self.cancel_edit();
+        }
+        self.update_message(tombstone(message, at, signature));
+        Ok(())
+    }
+
+    // режим выбора начинается с последнего сообщения ленты
+    pub fn start_selection(&mut self) -> bool {
+        let Some(position) = self.messages.iter().rposition(selectable) else {
+            return false;
+        };
+        self.selected = Some(self.messages[position].id.clone());
+        self.input_mode = InputMode::Normal;
+        self.reveal(position);
+        true
+    }
+
+    pub fn stop_selection(&mut self) {
+        self.selected = None;
+        self.input_mode = InputMode::Editing;
+    }
+
+    pub fn selected_message(&self) -> Option<&Message> {
+        self.selected_position()
+            .and_then(|position| self.messages.get(position))
+    }
+
+    fn selected_position(&self) -> Option<usize> {
+        let id = self.selected.as_ref()?;
+        self.messages.iter().position(|message| message.id == *id)
+    }
+
+    // шаг выбора по ленте; служебные строки пропускаем, у верха дотягиваем историю
+    pub fn move_selection(&mut self, up: bool) {
+        let Some(position) = self.selected_position() else {
+            return;
+        };
+        if up && !self.messages[..position].iter().any(selectable) {
+            self.load_older();
+        }
+        let Some(position) = self.selected_position() else {
+            return;
+        };
+        let next = if up {
+            self.messages[..position].iter().rposition(selectable)
+        } else {
+            self.messages[position + 1..]
+                .iter()
+                .position(selectable)
+                .map(|offset| position + 1 + offset)
+        };
+        if let Some(next) = next {
+            self.selected = Some(self.messages[next].id.clone());
+            self.reveal(next);
+        }
+    }
+
+    // прокручиваем ленту ровно настолько, чтобы сообщение position было на экране
+    fn reveal(&mut self, position: usize) {
+        let height = self.visible_height.max(1);
+        if position > self.scroll_offset {
+            self.scroll_offset = position;
+        } else if position + height <= self.scroll_offset {
+            self.scroll_offset = position + height - 1;
+        }
+    }
+
+    // ответ набирается как обычное сообщение, ссылка на выбранное уйдёт в reply_to
+    pub fn reply_to_selected(&mut self) {
+        self.replying = self.selected.clone();
+        self.stop_selection();
+    }
+
+    pub fn cancel_reply(&mut self) -> bool {
+        self.replying.take().is_some()
+    }
+
+    // ветка выбранного сообщения: корень его цепочки ответов и всё, что к нему ведёт
+    pub fn open_thread(&mut self) {
+        let Some(id) = self.selected.clone() else {
+            return;
+        };
+        self.stop_selection();
+        let root = self.thread_root(id);
+        let thread = self
+            .history
+            .lock()
+            .unwrap()
+            .thread(&self.current_room, &root);
+        let thread = match thread {
+            Ok(thread) if !thread.is_empty() => thread,
+            Ok(_) => {
+                self.add_notice("Ветка доступна только для сохранённых сообщений".to_string());
+                return;
+            }
+            Err(e) => {
+                self.add_notice(format!("Ошибка чтения истории: {}", e));
+                return;
+            }
+        };
+
+        self.messages = thread
+            .into_iter()
+            .map(|stored| {
+                if stored.untrusted {
+                    self.untrusted.insert(stored.message.id.clone());
+                }
+                stored.message
+            })
+            .collect();
+        self.thread = Some(root);
+        // ветка приходит целиком, подгружать по прокрутке нечего
+        self.history_exhausted = true;
+        self.scroll_to_bottom();
+    }
+
+    // поднимаемся по reply_to, пока предок есть у нас; повтор id обрывает подделанную петлю
+    fn thread_root(&self, id: String) -> String {
+        let mut seen = HashSet::new();
+        let mut root = id;
+        while seen.insert(root.clone())
+            && let Some(parent) = self
+                .find_message(&root)
+                .and_then(|message| message.reply_to)
+            && self.find_message(&parent).is_some()
+        {
+            root = parent;
+        }
+        root
+    }
+
+    // закрытие ветки возвращает полную ленту комнаты
+    pub fn close_thread(&mut self) -> bool {
+        if self.thread.is_none() {
+            return false;
+        }
+        self.switch_room(self.current_room.clone());
+        true
+    }
+
+    // написали что-то — значит, вернулись
+    fn mark_active(&mut self) {
+        self.set_away(false);
+        self.active_at = Instant::now();
+        // следующее сообщение снова сразу покажет, что мы печатаем
+        self.typing_sent = None;
+    }
+
+    // новое имя действует до конца сеанса; ключ подписи остаётся прежним
+    pub fn rename(&mut self, name: &str) -> Result<(), String> {
+        let valid = name.chars().count() <= MAX_NAME
+            && name
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(format!(
+                "некорректное имя {} (буквы, цифры, - и _, до {} символов)",
+                name, MAX_NAME
+            ));
+        }
+        if name == self.username {
+            return Err(format!("вас и так зовут {}", name));
+        }
+        self.username = name.to_string();
+        self.add_notice(format!("Теперь вы {}", name));
+        Ok(())
+    }
+
+    // лента пустеет до следующего переключения комнаты, история не трогается
+    pub fn clear_feed(&mut self) {
+        self.messages.clear();
+        self.scroll_offset = 0;
+        self.history_exhausted = true;
     }
 
     pub fn scroll_up(&mut self) {
+        // упёрлись в верх ленты — дотягиваем более старые сообщения из истории
+        let top = self
+            .scroll_offset
+            .saturating_sub(self.visible_height.saturating_sub(1));
+        if top == 0 {
+            self.load_older();
+        }
         if self.scroll_offset > 0 {
             self.scroll_offset -= 1;
         }
@@ -107,3 +1364,66 @@ impl UiState {
         &self.messages[start..end]
     }
 }
+
+// выбрать можно всё, кроме служебных строк самого чата
+fn selectable(message: &Message) -> bool {
+    message.kind != MessageKind::System
+}
+
+// надгробие: остаются id, автор, место в ленте и подпись удаления — по ней синхронизация его перешлёт
+fn tombstone(mut message: Message, at: i64, signature: String) -> Message {
+    let stamp = chrono::DateTime::from_timestamp_millis(at).unwrap_or_default();
+    message.content.clear();
+    message.metadata.clear();
+    message.metadata.insert(
+        DELETED.to_string(),
+        stamp.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+    );
+    message.signature = Some(signature);
+    message
+}
+
+fn now_stamp() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+// "bob" -> "@bob": под этим именем переписка живёт в истории и в списке комнат
+fn direct_conversation(user: &str) -> String {
+    format!("@{}", user)
+}
+
+// как переписку показывать человеку: комнаты с '#', личные как есть
+pub fn conversation_label(room: &str) -> String {
+    if room.starts_with('@') {
+        room.to_string()
+    } else {
+        format!("#{}", room)
+    }
+}
+
+// "#Rust" -> "rust"; пробелы и прочие символы в имени не пускаем
+fn room_name(name: &str) -> Option<String> {
+    let room = name.trim().trim_start_matches('#').to_lowercase();
+    let valid = !room.is_empty()
+        && room.chars().count() <= MAX_ROOM_NAME
+        && room
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    valid.then_some(room)
+}
+
+// 1536 -> "1.5 КБ"
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["Б", "КБ", "МБ", "ГБ"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}